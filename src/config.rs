@@ -1,7 +1,10 @@
+use crate::{Action, keybindings::KeyBindings};
 use clap::Parser;
 use color_eyre::{Result, eyre::eyre};
 use serde::Deserialize;
-use std::path::PathBuf;
+use std::{collections::BTreeMap, net::IpAddr, path::PathBuf, sync::Arc};
+
+const MAX_DETECT_LANGUAGES: usize = 10;
 
 #[derive(Parser)]
 pub struct Args {
@@ -9,15 +12,193 @@ pub struct Args {
     pub config: Option<PathBuf>,
 }
 
-#[derive(Clone, Deserialize)]
+#[derive(Clone, Default, Deserialize)]
 pub struct Config {
+    #[serde(default)]
+    pub backend: Backend,
     pub region: Option<String>,
     pub key: Option<String>,
+    /// Speech container or other host speaking the Speech service protocol,
+    /// e.g. `ws://localhost:5000`, used instead of the region's endpoint
+    pub speech_endpoint: Option<String>,
+    /// Locales offered in the language picker, the first being the default
+    #[serde(default = "Config::default_languages")]
+    pub languages: Vec<Arc<str>>,
+    /// Candidate locales when auto-detecting the language. Defaults to
+    /// `languages`.
+    #[serde(default)]
+    pub detect_languages: Vec<Arc<str>>,
+    /// Words and phrases filtered out according to the profanity setting,
+    /// in addition to any filtering done by the backend
+    pub profanity_blocklist: Option<PathBuf>,
     pub wordlist_dir: Option<PathBuf>,
     pub images_dir: Option<PathBuf>,
+    /// Where to write SRT and WebVTT transcripts when a Run session ends
+    pub transcript_dir: Option<PathBuf>,
+    /// Caption replays offered for the Test mode
+    pub test_data_dir: Option<PathBuf>,
+    /// Record the lines from the recogniser during each Run session, so that
+    /// the session can be replayed in the Test mode. The log is saved next
+    /// to the session's audio with the same name when the audio is being
+    /// recorded, and into `test_data_dir` otherwise.
+    #[serde(default)]
+    pub record_sessions: bool,
+    /// Port to serve the remote control API on, e.g. 80
+    pub http_port: Option<u16>,
+    /// Address to serve the remote control API on. It has no
+    /// authentication, so it defaults to localhost and must be set to e.g.
+    /// `0.0.0.0` to allow control from other machines.
+    pub http_address: Option<IpAddr>,
+    /// Path to the model directory used by the offline vosk backend
+    #[cfg_attr(not(feature = "vosk"), allow(dead_code))]
+    pub vosk_model: Option<PathBuf>,
+    /// Azure Translator resource for translated captions
+    pub translator: Option<TranslatorConfig>,
+    #[serde(default)]
+    pub audio: AudioConfig,
+    /// Physical buttons, for headless setups
+    pub gpio: Option<GpioConfig>,
+    #[serde(default)]
+    pub keybindings: KeyBindings,
+}
+
+/// Which speech recognition engine to use when running
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    #[default]
+    Azure,
+    /// Offline recognition using a local vosk model
+    Vosk,
+}
+
+/// Where audio is captured from
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct AudioConfig {
+    pub capture: Capture,
+    /// Audio system ffmpeg captures from
+    pub backend: AudioBackend,
+    /// Source to capture from, defaulting to the system default. Can be
+    /// overridden from the controls.
+    pub device: Option<String>,
+    /// Number of channels to capture
+    pub channels: u16,
+    /// Use only this channel, counting from 1, e.g. one output of a mixer
+    pub channel: Option<u16>,
+    pub gain_db: f32,
+    /// Recordings which can be replayed instead of capturing live audio
+    pub recordings_dir: Option<PathBuf>,
+    /// Save the audio sent to the recogniser into `recordings_dir`
+    pub record: bool,
+    /// RMS level in dBFS below which the input counts as silent
+    pub silence_threshold_db: f32,
+    /// Seconds of silence or clipping before the controls raise an alarm
+    pub alarm_after_secs: u64,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        Self {
+            capture: Capture::default(),
+            backend: AudioBackend::default(),
+            device: None,
+            channels: 2,
+            channel: None,
+            gain_db: 0.0,
+            recordings_dir: None,
+            record: false,
+            silence_threshold_db: -50.0,
+            alarm_after_secs: 10,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capture {
+    /// Run ffmpeg, capturing from `backend`
+    #[cfg_attr(not(feature = "native-audio"), default)]
+    Ffmpeg,
+    /// Capture in-process through the system audio API, so that ffmpeg need
+    /// not be installed. Requires building with `--features native-audio`,
+    /// which makes it the default.
+    #[cfg_attr(feature = "native-audio", default)]
+    Native,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AudioBackend {
+    #[default]
+    Pulse,
+    Alsa,
+    Pipewire,
+    Jack,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TranslatorConfig {
+    pub region: String,
+    pub key: String,
+    #[serde(default = "TranslatorConfig::default_endpoint")]
+    pub endpoint: String,
+    /// Target languages offered in the controls, e.g. `["ja", "uk"]`
+    pub languages: Vec<Arc<str>>,
+}
+
+impl TranslatorConfig {
+    fn default_endpoint() -> String {
+        "https://api.cognitive.microsofttranslator.com".into()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct GpioConfig {
+    /// GPIO character device, e.g. `/dev/gpiochip0`
+    #[serde(default = "GpioConfig::default_chip")]
+    pub chip: PathBuf,
+    #[serde(default = "GpioConfig::default_debounce_ms")]
+    pub debounce_ms: u64,
+    /// Line offset on the chip for each action
+    pub pins: BTreeMap<Action, u32>,
+}
+
+impl GpioConfig {
+    fn default_chip() -> PathBuf {
+        "/dev/gpiochip0".into()
+    }
+
+    const fn default_debounce_ms() -> u64 {
+        50
+    }
 }
 
 impl Config {
+    fn default_languages() -> Vec<Arc<str>> {
+        crate::LANGUAGE_OPTIONS
+            .iter()
+            .map(|&lang| lang.into())
+            .collect()
+    }
+
+    /// Languages which captions can be translated into
+    pub fn translation_languages(&self) -> &[Arc<str>] {
+        self.translator
+            .as_ref()
+            .map(|translator| translator.languages.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Locales considered when auto-detecting the spoken language
+    pub fn detect_languages(&self) -> &[Arc<str>] {
+        if self.detect_languages.is_empty() {
+            &self.languages
+        } else {
+            &self.detect_languages
+        }
+    }
+
     pub fn load(path: Option<PathBuf>) -> Result<Self> {
         let path = if let Some(path) = path {
             path
@@ -37,6 +218,77 @@ impl Config {
             }
         };
         let content = std::fs::read_to_string(path)?;
-        toml::de::from_str(&content).map_err(Into::into)
+        let config: Self = toml::de::from_str(&content)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.languages.is_empty() {
+            return Err(eyre!("At least one language must be configured"));
+        }
+        // Limit imposed by Azure for continuous language identification
+        if self.detect_languages().len() > MAX_DETECT_LANGUAGES {
+            return Err(eyre!(
+                "At most {MAX_DETECT_LANGUAGES} languages can be auto-detected, \
+                set `detect_languages` to choose the candidates"
+            ));
+        }
+
+        if self.record_sessions && self.test_data_dir.is_none() {
+            return Err(eyre!("`record_sessions` requires `test_data_dir`"));
+        }
+        if self.audio.record && self.audio.recordings_dir.is_none() {
+            return Err(eyre!("Audio `record` requires `recordings_dir`"));
+        }
+
+        if let Some(gpio) = &self.gpio {
+            let mut seen = BTreeMap::new();
+            for (action, pin) in &gpio.pins {
+                if action.needs_window() {
+                    return Err(eyre!(
+                        "{action:?} can only be triggered from the keyboard, \
+                        not GPIO pin {pin}"
+                    ));
+                }
+                if let Some(other) = seen.insert(pin, action) {
+                    return Err(eyre!(
+                        "GPIO pin {pin} is assigned to both {other:?} and \
+                        {action:?}"
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn gpio_config(pins: &str) -> Result<()> {
+        let config: Config =
+            toml::de::from_str(&format!("[gpio.pins]\n{pins}"))?;
+        config.validate()
+    }
+
+    #[test]
+    fn test_gpio_pins() {
+        gpio_config("toggle_running = 17\nclear = 27").unwrap();
+        assert_eq!(
+            gpio_config("toggle_running = 17\nclear = 17")
+                .unwrap_err()
+                .to_string(),
+            "GPIO pin 17 is assigned to both ToggleRunning and Clear"
+        );
+        assert_eq!(
+            gpio_config("toggle_fullscreen = 17")
+                .unwrap_err()
+                .to_string(),
+            "ToggleFullscreen can only be triggered from the keyboard, not \
+            GPIO pin 17"
+        );
     }
 }
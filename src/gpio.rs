@@ -0,0 +1,227 @@
+//! Physical buttons on GPIO lines, read through the Linux GPIO character
+//! device
+//!
+//! Buttons are expected to short the line to ground, so lines are requested
+//! with a pull-up and treated as active-low. An action fires on press.
+//!
+//! Contact bounce is filtered out here using the event timestamps rather
+//! than by the kernel, so that the filtering can be tested without the
+//! gpio-sim module.
+
+use crate::{Action, Result, config::GpioConfig};
+use gpiocdev::{
+    Request,
+    line::{Bias, EdgeDetection, EdgeKind},
+};
+use std::{collections::HashMap, time::Duration};
+
+const CONSUMER: &str = "captioninator";
+
+/// Request the configured lines and call `on_press` from a background thread
+/// whenever a button is pressed
+pub fn start(
+    config: GpioConfig,
+    on_press: impl FnMut(Action) + Send + 'static,
+) -> Result<()> {
+    let request = request(&config)?;
+    let actions = config
+        .pins
+        .iter()
+        .map(|(&action, &pin)| (pin, action))
+        .collect::<HashMap<_, _>>();
+
+    let debounce = Duration::from_millis(config.debounce_ms);
+
+    info!("Listening for GPIO buttons on {}", config.chip.display());
+    std::thread::Builder::new()
+        .name("gpio".into())
+        .spawn(move || watch(&request, &actions, debounce, on_press))?;
+
+    Ok(())
+}
+
+fn request(config: &GpioConfig) -> Result<Request> {
+    let pins = config.pins.values().copied().collect::<Vec<_>>();
+    Request::builder()
+        .on_chip(&config.chip)
+        .with_consumer(CONSUMER)
+        .with_lines(&pins)
+        .as_input()
+        .as_active_low()
+        .with_bias(Bias::PullUp)
+        .with_edge_detection(EdgeDetection::BothEdges)
+        .request()
+        .map_err(Into::into)
+}
+
+/// Tells presses apart from contact bounce, which shows up as a burst of
+/// edges. A press only counts if its line had been still for the debounce
+/// period beforehand.
+struct Debouncer {
+    period: Duration,
+    /// Time of the latest edge on each line
+    last_edges: HashMap<u32, Duration>,
+}
+
+impl Debouncer {
+    fn new(period: Duration) -> Self {
+        Self {
+            period,
+            last_edges: HashMap::new(),
+        }
+    }
+
+    /// Note an edge on `line` at `timestamp`, giving whether it is a press
+    fn is_press(
+        &mut self,
+        line: u32,
+        kind: EdgeKind,
+        timestamp: Duration,
+    ) -> bool {
+        let still = self
+            .last_edges
+            .insert(line, timestamp)
+            .is_none_or(|last| timestamp.saturating_sub(last) >= self.period);
+        kind == EdgeKind::Rising && still
+    }
+}
+
+fn watch(
+    request: &Request,
+    actions: &HashMap<u32, Action>,
+    debounce: Duration,
+    mut on_press: impl FnMut(Action),
+) {
+    let mut debouncer = Debouncer::new(debounce);
+    for event in request.edge_events() {
+        let event = match event {
+            Ok(event) => event,
+            Err(err) => {
+                error!("Failed to read GPIO event: {err}");
+                break;
+            }
+        };
+        let timestamp = Duration::from_nanos(event.timestamp_ns);
+        if !debouncer.is_press(event.offset, event.kind, timestamp) {
+            continue;
+        }
+        if let Some(&action) = actions.get(&event.offset) {
+            debug!("GPIO line {} pressed: {action:?}", event.offset);
+            on_press(action);
+        }
+    }
+    warn!("GPIO button thread stopped");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::{collections::BTreeMap, sync::mpsc};
+
+    const TIMEOUT: Duration = Duration::from_secs(2);
+
+    fn simulate(
+        pins: &[(Action, u32)],
+    ) -> (gpiosim::Simpleton, mpsc::Receiver<Action>) {
+        let sim = gpiosim::Simpleton::new(8);
+        // Buttons are released, so the lines idle high
+        for &(_, pin) in pins {
+            sim.pullup(pin).unwrap();
+        }
+
+        let (tx, rx) = mpsc::channel();
+        start(
+            GpioConfig {
+                chip: sim.dev_path().clone(),
+                debounce_ms: 5,
+                pins: pins.iter().copied().collect::<BTreeMap<_, _>>(),
+            },
+            move |action| tx.send(action).unwrap(),
+        )
+        .unwrap();
+
+        (sim, rx)
+    }
+
+    fn press(sim: &gpiosim::Simpleton, pin: u32) {
+        sim.pulldown(pin).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        sim.pullup(pin).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+    }
+
+    #[test]
+    fn test_debouncer() {
+        let ms = Duration::from_millis;
+        let mut debouncer = Debouncer::new(ms(50));
+        let mut presses = |edges: &[(u32, EdgeKind, u64)]| {
+            edges
+                .iter()
+                .filter(|&&(line, kind, time)| {
+                    debouncer.is_press(line, kind, ms(time))
+                })
+                .map(|&(line, _, time)| (line, time))
+                .collect::<Vec<_>>()
+        };
+
+        // A press which bounces, followed by a release which bounces
+        assert_eq!(
+            presses(&[
+                (1, EdgeKind::Rising, 1000),
+                (1, EdgeKind::Falling, 1001),
+                (1, EdgeKind::Rising, 1003),
+                (1, EdgeKind::Falling, 1200),
+                (1, EdgeKind::Rising, 1202),
+                (1, EdgeKind::Falling, 1204),
+            ]),
+            [(1, 1000)]
+        );
+        // Another line pressed during the bounce, then the first pressed
+        // again once it has settled
+        assert_eq!(
+            presses(&[
+                (2, EdgeKind::Rising, 1205),
+                (1, EdgeKind::Rising, 1260),
+            ]),
+            [(2, 1205), (1, 1260)]
+        );
+    }
+
+    #[test]
+    #[ignore = "requires the gpio-sim kernel module"]
+    fn test_button_press() {
+        let (sim, rx) = simulate(&[
+            (Action::ToggleRunning, 1),
+            (Action::ToggleHoldingSlide, 2),
+            (Action::Stop, 3),
+            (Action::Clear, 4),
+        ]);
+
+        for (pin, action) in [
+            (1, Action::ToggleRunning),
+            (2, Action::ToggleHoldingSlide),
+            (3, Action::Stop),
+            (4, Action::Clear),
+        ] {
+            press(&sim, pin);
+            assert_eq!(rx.recv_timeout(TIMEOUT).unwrap(), action);
+        }
+    }
+
+    #[test]
+    #[ignore = "requires the gpio-sim kernel module"]
+    fn test_debounce() {
+        let (sim, rx) = simulate(&[(Action::ToggleRunning, 0)]);
+
+        // Contact bounce shorter than the debounce period
+        for _ in 0..5 {
+            sim.pulldown(0).unwrap();
+            sim.pullup(0).unwrap();
+        }
+        press(&sim, 0);
+
+        assert_eq!(rx.recv_timeout(TIMEOUT).unwrap(), Action::ToggleRunning);
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+}
@@ -1,7 +1,9 @@
-use crate::DisplayMode;
+use crate::{ControlState, DisplayMode, TranslationLayout, Utterance, Word};
 use egui::{
+    Align, Color32, FontSelection, Frame, Layout, Margin, Rect, RichText, Ui,
+    Vec2, WidgetText,
     scroll_area::{ScrollBarVisibility, ScrollSource},
-    Align, Color32, Frame, Layout, Margin, Rect, RichText, Vec2,
+    text::LayoutJob,
 };
 
 //TODO figure out a better way to shift the subtitle mode away from the
@@ -71,13 +73,41 @@ pub fn show(app: &mut crate::gui::MyApp, ctx: &egui::Context) {
                 .max_width(max_width)
                 .show(ui, |ui| {
                     ui.with_layout(Layout::top_down(Align::Min), |ui| {
+                        let columns = control_state.translation.is_some()
+                            && control_state.translation_layout
+                                == TranslationLayout::Columns;
                         for line in
                             app.text_buffer.iter().chain(&app.active_line)
                         {
-                            ui.label(
-                                RichText::new(line)
-                                    .size(control_state.font_size()),
-                            );
+                            let translation =
+                                line.translation.as_deref().map(|text| {
+                                    RichText::new(text)
+                                        .size(control_state.font_size())
+                                        .color(base_theme.subtext0)
+                                });
+                            if columns {
+                                ui.columns(2, |columns| {
+                                    utterance(
+                                        &mut columns[0],
+                                        line,
+                                        &control_state,
+                                        &base_theme,
+                                    );
+                                    if let Some(translation) = translation {
+                                        columns[1].label(translation);
+                                    }
+                                });
+                            } else {
+                                utterance(
+                                    ui,
+                                    line,
+                                    &control_state,
+                                    &base_theme,
+                                );
+                                if let Some(translation) = translation {
+                                    ui.label(translation);
+                                }
+                            }
                         }
                     });
                     // });
@@ -88,3 +118,163 @@ pub fn show(app: &mut crate::gui::MyApp, ctx: &egui::Context) {
         styles.visuals.panel_fill = base_theme.base;
     });
 }
+
+/// Show a line of captions, tagged with its language if it was detected and
+/// prefixed with the speaker's name if known
+fn utterance(
+    ui: &mut Ui,
+    line: &Utterance,
+    control_state: &ControlState,
+    theme: &catppuccin_egui::Theme,
+) {
+    let text =
+        if control_state.highlight_low_confidence && !line.words.is_empty() {
+            highlighted(ui, line, control_state, theme).into()
+        } else {
+            WidgetText::from(
+                RichText::new(&line.text).size(control_state.font_size()),
+            )
+        };
+    if line.language.is_none() && line.speaker.is_none() {
+        ui.label(text);
+        return;
+    }
+    ui.horizontal_wrapped(|ui| {
+        if let Some(language) = &line.language {
+            let index = control_state
+                .language_options
+                .iter()
+                .position(|option| option == language);
+            ui.label(
+                RichText::new(language.as_ref())
+                    .size(control_state.font_size() * 0.4)
+                    .color(tag_colour(theme, index)),
+            );
+        }
+        if let Some(speaker) = &line.speaker {
+            let index = control_state
+                .speaker_names
+                .keys()
+                .position(|id| id == speaker);
+            ui.label(
+                RichText::new(format!(
+                    "{}:",
+                    control_state.speaker_name(speaker)
+                ))
+                .size(control_state.font_size())
+                .color(tag_colour(theme, index)),
+            );
+        }
+        ui.label(text);
+    });
+}
+
+/// The text of a finished line with words the recogniser was unsure of
+/// dimmed and italicised
+fn highlighted(
+    ui: &Ui,
+    line: &Utterance,
+    control_state: &ControlState,
+    theme: &catppuccin_egui::Theme,
+) -> LayoutJob {
+    let mut job = LayoutJob::default();
+    for (token, confidence) in word_confidences(&line.text, &line.words) {
+        let mut text = RichText::new(token).size(control_state.font_size());
+        if confidence.is_some_and(|confidence| {
+            confidence < control_state.confidence_threshold
+        }) {
+            text = text.italics().color(theme.overlay1);
+        }
+        text.append_to(
+            &mut job,
+            ui.style(),
+            FontSelection::Default,
+            Align::Min,
+        );
+    }
+    job
+}
+
+/// Pair each word of the displayed text, including its trailing whitespace,
+/// with the recogniser's confidence in it. The displayed text is punctuated
+/// and may have numbers etc reformatted, so words are matched loosely and in
+/// order, leaving any which cannot be matched without a confidence.
+fn word_confidences<'a>(
+    text: &'a str,
+    words: &[Word],
+) -> Vec<(&'a str, Option<f32>)> {
+    const LOOKAHEAD: usize = 3;
+    let normalise = |word: &str| {
+        word.chars()
+            .filter(|c| c.is_alphanumeric())
+            .flat_map(char::to_lowercase)
+            .collect::<String>()
+    };
+
+    let mut remaining = words;
+    text.split_inclusive(char::is_whitespace)
+        .map(|token| {
+            let wanted = normalise(token);
+            let found = remaining
+                .iter()
+                .take(LOOKAHEAD)
+                .position(|word| normalise(&word.text) == wanted);
+            let confidence = found.and_then(|index| {
+                let confidence = remaining[index].confidence;
+                remaining = &remaining[index + 1..];
+                confidence
+            });
+            (token, confidence)
+        })
+        .collect()
+}
+
+/// Colour for tagging lines by language or speaker. Keyed on a position in
+/// the controls so that it is stable between lines.
+fn tag_colour(theme: &catppuccin_egui::Theme, index: Option<usize>) -> Color32 {
+    let palette = [
+        theme.blue,
+        theme.peach,
+        theme.green,
+        theme.mauve,
+        theme.teal,
+        theme.yellow,
+    ];
+    palette[index.unwrap_or(palette.len() - 1) % palette.len()]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn word(text: &str, confidence: f32) -> Word {
+        Word {
+            text: text.into(),
+            offset: None,
+            duration: None,
+            confidence: Some(confidence),
+        }
+    }
+
+    #[test]
+    fn test_word_confidences() {
+        let words = [
+            word("hello", 0.9),
+            word("world", 0.3),
+            word("twenty", 0.6),
+            word("three", 0.6),
+            word("apples", 0.8),
+        ];
+        assert_eq!(
+            word_confidences("Hello, world.  23 apples", &words),
+            [
+                ("Hello, ", Some(0.9)),
+                ("world. ", Some(0.3)),
+                (" ", None),
+                ("23 ", None),
+                ("apples", Some(0.8)),
+            ]
+        );
+    }
+}
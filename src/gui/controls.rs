@@ -1,13 +1,21 @@
 use crate::{
-    DisplayMode, MAX_FONT, MAX_SUBTITLE_HEIGHT, MIN_FONT, MIN_SUBTITLE_HEIGHT,
-    RunState,
+    Action, DisplayMode, MAX_FONT, MAX_REPLAY_SPEED, MAX_SUBTITLE_HEIGHT,
+    MIN_FONT, MIN_SUBTITLE_HEIGHT, ProfanityMode, RunState, TranslationLayout,
+    gui::{moderation, signal},
+    listener::{AudioLevel, Connection, Status},
+};
+use egui::{
+    Button, Color32, ComboBox, ProgressBar, RichText, Slider, TextEdit, Ui,
 };
-use egui::{Button, ComboBox, RichText, Slider, Ui};
 use std::{
     ops::DerefMut,
     sync::{Arc, Mutex, atomic::Ordering},
+    time::{Duration, Instant},
 };
 
+/// Quietest level shown on the meter, in dBFS
+const METER_FLOOR_DB: f32 = -60.0;
+
 pub fn show(ui: &mut Ui, app: &mut crate::ControlState) {
     ui.heading(RichText::new("Captions").size(50.0));
 
@@ -22,10 +30,15 @@ pub fn show(ui: &mut Ui, app: &mut crate::ControlState) {
         .text("Subtitle height"),
     );
 
-    ui.checkbox(&mut app.dark_mode_requested, "Dark Mode [d]");
+    let dark_mode_label =
+        format!("Dark Mode{}", app.key_hint(Action::ToggleDarkMode));
+    ui.checkbox(&mut app.dark_mode_requested, dark_mode_label);
 
     ui.horizontal(|ui| {
-        ui.label("Display mode [m]");
+        ui.label(format!(
+            "Display mode{}",
+            app.key_hint(Action::SwapDisplayMode)
+        ));
         ComboBox::from_id_salt("display_mode")
             .selected_text(format!("{:?}", app.display_mode))
             .show_ui(ui, |ui| {
@@ -63,6 +76,187 @@ pub fn show(ui: &mut Ui, app: &mut crate::ControlState) {
         }
     });
 
+    ui.horizontal(|ui| {
+        let before = app.language.clone();
+        ui.label("Language");
+        let auto_detect = app.auto_detect_language;
+        ui.add_enabled_ui(!app.auto_detect_language, |ui| {
+            ComboBox::from_id_salt("language")
+                .selected_text(app.language.as_ref())
+                .show_ui(ui, |ui| {
+                    for option in &app.language_options {
+                        ui.selectable_value(
+                            &mut app.language,
+                            option.clone(),
+                            option.as_ref(),
+                        );
+                    }
+                });
+        });
+        ui.checkbox(&mut app.auto_detect_language, "Auto-detect");
+        if app.language != before || app.auto_detect_language != auto_detect {
+            app.update_language();
+        }
+    });
+
+    if !app.translation_options.is_empty() {
+        ui.horizontal(|ui| {
+            let current = app.translation.as_deref().unwrap_or("None");
+            let before = app.translation.clone();
+            ui.label("Translate to:");
+            ComboBox::from_id_salt("translation")
+                .selected_text(current)
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut app.translation, None, "None");
+                    for option in &app.translation_options {
+                        ui.selectable_value(
+                            &mut app.translation,
+                            Some(option.clone()),
+                            option.as_ref(),
+                        );
+                    }
+                });
+            if app.translation != before {
+                app.update_translation();
+            }
+            ui.selectable_value(
+                &mut app.translation_layout,
+                TranslationLayout::Paired,
+                "Paired",
+            );
+            ui.selectable_value(
+                &mut app.translation_layout,
+                TranslationLayout::Columns,
+                "Columns",
+            );
+        });
+    }
+
+    ui.horizontal(|ui| {
+        let current = app.audio_device.as_deref().unwrap_or("Default");
+        let before = app.audio_device.clone();
+        ui.label("Audio input:");
+        ComboBox::from_id_salt("audio_device")
+            .selected_text(current)
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut app.audio_device, None, "Default");
+                for option in &app.audio_device_options {
+                    ui.selectable_value(
+                        &mut app.audio_device,
+                        Some(option.clone()),
+                        option.as_ref(),
+                    );
+                }
+            });
+        if ui.button("Refresh").clicked() {
+            app.audio_device_options =
+                crate::listener::list_audio_devices(&app.audio_config);
+        }
+        if app.audio_device != before {
+            app.update_audio_device();
+        }
+    });
+
+    ui.horizontal(|ui| {
+        let current = app.replay.as_deref().unwrap_or("Off");
+        let before = app.replay.clone();
+        ui.label("Replay:");
+        ComboBox::from_id_salt("replay")
+            .selected_text(current)
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut app.replay, None, "Off");
+                for option in &app.replay_options {
+                    ui.selectable_value(
+                        &mut app.replay,
+                        Some(option.clone()),
+                        option.as_ref(),
+                    );
+                }
+            });
+        ui.add(
+            TextEdit::singleline(&mut app.replay_url)
+                .hint_text("or stream URL")
+                .desired_width(200.0),
+        );
+        if ui.button("Refresh").clicked() {
+            app.refresh_replays();
+        }
+        if ui.button("Use URL").clicked() && !app.replay_url.is_empty() {
+            app.replay = Some(app.replay_url.trim().into());
+        }
+        let speed = ui.add(
+            Slider::new(&mut app.replay_speed, 1.0..=MAX_REPLAY_SPEED)
+                .text("Speed"),
+        );
+        // Changing the speed restarts the replay, so wait until the slider
+        // is let go
+        let speed_changed =
+            speed.drag_stopped() || (speed.changed() && !speed.dragged());
+        if app.replay != before || (app.replay.is_some() && speed_changed) {
+            app.update_replay();
+        }
+    });
+
+    let level = *app.audio_level.borrow();
+    let now = Instant::now();
+    app.signal_monitor.update(level, now);
+    level_meter(ui, level, app.signal_monitor.alarm(now));
+
+    ui.horizontal(|ui| {
+        let before = app.profanity;
+        ui.label("Profanity:");
+        for (mode, label) in [
+            (ProfanityMode::Raw, "Raw"),
+            (ProfanityMode::Masked, "Masked"),
+            (ProfanityMode::Removed, "Removed"),
+        ] {
+            ui.selectable_value(&mut app.profanity, mode, label);
+        }
+        if app.profanity != before {
+            app.update_profanity();
+        }
+    });
+
+    ui.horizontal(|ui| {
+        ui.checkbox(
+            &mut app.highlight_low_confidence,
+            "Highlight uncertain words",
+        );
+        ui.add_enabled(
+            app.highlight_low_confidence,
+            Slider::new(&mut app.confidence_threshold, 0.0..=1.0)
+                .text("Confidence threshold"),
+        );
+    });
+
+    ui.horizontal(|ui| {
+        ui.checkbox(&mut app.moderation_enabled, "Moderate");
+        ui.add(
+            Slider::new(
+                &mut app.moderation_delay,
+                moderation::MIN_DELAY..=moderation::MAX_DELAY,
+            )
+            .text("Delay (s)"),
+        );
+    });
+    if app.moderation_enabled {
+        pending_lines(ui, &mut app.moderation_queue);
+    }
+
+    if !app.speaker_names.is_empty() {
+        ui.collapsing("Speakers", |ui| {
+            for (speaker, name) in &mut app.speaker_names {
+                ui.horizontal(|ui| {
+                    ui.label(speaker.as_ref());
+                    ui.add(TextEdit::singleline(name).hint_text("Name"));
+                });
+            }
+            if ui.button("Forget speakers").clicked() {
+                app.speaker_names.clear();
+            }
+        });
+    }
+
     ui.horizontal(|ui| {
         let current_image = app.selected_image.as_deref().unwrap_or("None");
         let before = app.selected_image.clone();
@@ -85,22 +279,52 @@ pub fn show(ui: &mut Ui, app: &mut crate::ControlState) {
     });
 
     ui.horizontal(|ui| {
-        if button(ui, "Run [space]", app.run_state == RunState::Running) {
+        let current = app.test_data.as_deref().unwrap_or("Built-in");
+        let before = (app.test_data.clone(), app.test_loop);
+        ui.label("Test data:");
+        ComboBox::from_id_salt("test_data")
+            .selected_text(current)
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut app.test_data, None, "Built-in");
+                for option in &app.test_data_options {
+                    ui.selectable_value(
+                        &mut app.test_data,
+                        Some(option.clone()),
+                        option.as_ref(),
+                    );
+                }
+            });
+        ui.checkbox(&mut app.test_loop, "Loop");
+        if ui.button("Refresh").clicked() {
+            app.refresh_replays();
+        }
+        if (app.test_data.clone(), app.test_loop) != before {
+            app.update_test_data();
+        }
+    });
+
+    app.sync_listener_status();
+    ui.horizontal(|ui| {
+        let run_label = format!("Run{}", app.key_hint(Action::ToggleRunning));
+        if button(ui, &run_label, app.run_state == RunState::Running) {
             app.toggle_running();
         }
 
-        if button(ui, "Test [t]", app.run_state == RunState::Test) {
+        let test_label =
+            format!("Test{}", app.key_hint(Action::ToggleTestMode));
+        if button(ui, &test_label, app.run_state == RunState::Test) {
             app.toggle_test_mode();
         }
 
-        if button(
-            ui,
-            "Holding image [h]",
-            app.run_state == RunState::HoldingSlide,
-        ) {
+        let holding_label = format!(
+            "Holding image{}",
+            app.key_hint(Action::ToggleHoldingSlide)
+        );
+        if button(ui, &holding_label, app.run_state == RunState::HoldingSlide) {
             app.toggle_holding_slide();
         }
     });
+    listener_status(ui, &app.listener_status.borrow());
 
     ui.horizontal(|ui| {
         if ui.button("Exit").clicked() {
@@ -111,6 +335,12 @@ pub fn show(ui: &mut Ui, app: &mut crate::ControlState) {
             app.request_clear.store(true, Ordering::Relaxed);
         }
     });
+
+    ui.collapsing("Keyboard shortcuts", |ui| {
+        for (shortcut, action) in app.keybindings.iter() {
+            ui.label(format!("{shortcut}: {action:?}"));
+        }
+    });
 }
 
 fn button(ui: &mut Ui, text: &str, selected: bool) -> bool {
@@ -141,3 +371,129 @@ pub fn window(
         show(ui, control_state.deref_mut());
     });
 }
+
+/// RMS level as a bar, with a warning once the input has been silent or
+/// clipping for too long
+fn level_meter(
+    ui: &mut Ui,
+    level: Option<AudioLevel>,
+    alarm: Option<signal::Alarm>,
+) {
+    ui.horizontal(|ui| {
+        ui.label("Level:");
+        let Some(level) = level else {
+            ui.label("Not capturing");
+            return;
+        };
+
+        let fraction =
+            ((level.rms - METER_FLOOR_DB) / -METER_FLOOR_DB).clamp(0.0, 1.0);
+        let fill = if alarm.is_some() {
+            Color32::RED
+        } else if level.peak >= signal::CLIPPING_DB {
+            Color32::ORANGE
+        } else {
+            Color32::DARK_GREEN
+        };
+        ui.add(
+            ProgressBar::new(fraction)
+                .desired_width(200.0)
+                .fill(fill)
+                .text(format!("{:.0} dB", level.rms.max(METER_FLOOR_DB))),
+        );
+
+        match alarm {
+            Some(signal::Alarm::Silent) => {
+                ui.label(
+                    RichText::new("NO SIGNAL - check the input is unmuted")
+                        .color(Color32::RED)
+                        .strong(),
+                );
+            }
+            Some(signal::Alarm::Clipping) => {
+                ui.label(
+                    RichText::new("CLIPPING - turn the input down")
+                        .color(Color32::RED)
+                        .strong(),
+                );
+            }
+            None => {}
+        }
+
+        // Keep the meter moving
+        ui.ctx().request_repaint_after(Duration::from_millis(100));
+    });
+}
+
+/// Connection to the recogniser and its last error
+fn listener_status(ui: &mut Ui, status: &Status) {
+    ui.horizontal(|ui| {
+        match status.connection {
+            Connection::Idle => {}
+            Connection::Connecting => {
+                ui.label("Connecting...");
+            }
+            Connection::Listening => {
+                ui.colored_label(Color32::DARK_GREEN, "Listening");
+            }
+            Connection::Reconnecting { attempt, retry_at } => {
+                let wait = retry_at.saturating_duration_since(Instant::now());
+                ui.colored_label(
+                    Color32::ORANGE,
+                    format!(
+                        "Reconnecting in {:.0} s (attempt {attempt})",
+                        wait.as_secs_f32().ceil()
+                    ),
+                );
+            }
+            Connection::Failed => {
+                ui.label(
+                    RichText::new("FAILED - press Run to try again")
+                        .color(Color32::RED)
+                        .strong(),
+                );
+            }
+        }
+        if let Some(err) = &status.last_error {
+            ui.label(RichText::new(err).color(Color32::RED));
+        }
+    });
+
+    // Watch for the listener giving up or reconnecting
+    if status.run_state == RunState::Running {
+        ui.ctx().request_repaint_after(Duration::from_millis(250));
+    }
+}
+
+/// Lines waiting to be shown, which the operator can edit, drop or release
+fn pending_lines(ui: &mut Ui, queue: &mut moderation::Queue) {
+    if queue.is_empty() {
+        ui.label("No lines waiting");
+        return;
+    }
+
+    let now = Instant::now();
+    let mut dropped = None;
+    for (index, pending) in queue.iter_mut().enumerate() {
+        ui.horizontal(|ui| {
+            let edit =
+                ui.add(TextEdit::singleline(&mut pending.utterance.text));
+            if edit.has_focus() || edit.changed() {
+                pending.hold(now);
+            }
+            ui.label(format!("{:.0}s", pending.remaining(now).as_secs_f32()));
+            if ui.button("Release").clicked() {
+                pending.release_now(now);
+            }
+            if ui.button("Drop").clicked() {
+                dropped = Some(index);
+            }
+        });
+    }
+    if let Some(index) = dropped {
+        queue.remove(index);
+    }
+
+    // Keep the countdowns moving
+    ui.ctx().request_repaint_after(Duration::from_millis(250));
+}
@@ -1,52 +1,19 @@
 use crate::{
-    MAX_FONT, MAX_SUBTITLE_HEIGHT, MIN_FONT, MIN_SUBTITLE_HEIGHT, gui::State,
+    Action, MAX_FONT, MAX_SUBTITLE_HEIGHT, MIN_FONT, MIN_SUBTITLE_HEIGHT,
 };
-use egui::{Context, Key, ViewportCommand};
+use egui::{Context, ViewportCommand};
 
 pub fn process(ctx: &Context, app: &mut crate::ControlState) {
-    if ctx.input(|i| i.key_pressed(Key::F1)) {
-        app.state = State::Config;
-    }
-    if ctx.input(|i| i.key_pressed(Key::Escape)) {
-        app.state = State::Normal;
-    }
-
-    if ctx.input(|i| i.key_pressed(Key::Minus)) {
-        *app.font_size_mut() -= 1.0;
-    }
-    if ctx.input(|i| i.key_pressed(Key::Equals)) {
-        *app.font_size_mut() += 1.0;
-    }
-
-    if ctx.input(|i| i.key_pressed(Key::ArrowUp)) {
-        app.subtitle_height_proportion += 0.1;
-    }
-    if ctx.input(|i| i.key_pressed(Key::ArrowDown)) {
-        app.subtitle_height_proportion -= 0.1;
-    }
-
-    if ctx.input(|i| i.key_pressed(Key::D)) {
-        app.dark_mode_requested = !app.dark_mode_enabled;
-    }
-
-    if ctx.input(|i| i.key_pressed(Key::M)) {
-        app.display_mode.swap();
-    }
-
-    if ctx.input(|i| i.key_pressed(Key::Space)) {
-        app.toggle_running();
-    }
-
-    if ctx.input(|i| i.key_pressed(Key::T)) {
-        app.toggle_test_mode();
-    }
-
-    if ctx.input(|i| i.key_pressed(Key::H)) {
-        app.toggle_holding_slide();
-    }
-
-    if ctx.input(|i| i.key_pressed(Key::F11)) {
-        toggle_fullscreen(ctx);
+    // Text fields don't consume key events, so leave the shortcuts alone
+    // while one has focus, or typing a space would stop the captions
+    if !ctx.wants_keyboard_input() {
+        let actions = ctx.input(|i| app.keybindings.pressed(i));
+        for action in actions {
+            match action {
+                Action::ToggleFullscreen => toggle_fullscreen(ctx),
+                action => app.apply(action),
+            }
+        }
     }
 
     *app.font_size_mut() = app.font_size().clamp(MIN_FONT, MAX_FONT);
@@ -65,3 +32,100 @@ fn toggle_fullscreen(ctx: &egui::Context) {
     });
     ctx.send_viewport_cmd(ViewportCommand::Fullscreen(!is_fullscreen));
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        ControlMessage, RunState, Wordlist,
+        config::{AudioBackend, AudioConfig, Config},
+        gui::MyApp,
+        xrandr::MonitorPositions,
+    };
+    use egui::{Key, Modifiers};
+    use pretty_assertions::assert_eq;
+    use std::sync::{Arc, Mutex};
+    use tokio::sync::{broadcast, mpsc, watch};
+
+    async fn control_state() -> Arc<Mutex<crate::ControlState>> {
+        let (control_tx, mut control_rx) = mpsc::channel(10);
+        let config = Config {
+            languages: vec!["en-GB".into()],
+            audio: AudioConfig {
+                backend: AudioBackend::Jack,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (app, ()) = tokio::join!(
+            MyApp::new(
+                mpsc::channel(1).1,
+                broadcast::channel(1).0,
+                config,
+                control_tx,
+                watch::channel(None).1,
+                watch::channel(Default::default()).1,
+                MonitorPositions {
+                    internal: Default::default(),
+                    external: Default::default(),
+                },
+            ),
+            async {
+                let Some(ControlMessage::GetWordlist(tx)) =
+                    control_rx.recv().await
+                else {
+                    panic!("Expected the wordlist to be requested");
+                };
+                tx.send(Wordlist {
+                    options: Vec::new(),
+                    current: None,
+                })
+                .unwrap();
+            },
+        );
+        app.unwrap().control_state()
+    }
+
+    #[tokio::test]
+    async fn test_shortcuts_ignored_while_typing() {
+        let control_state = control_state().await;
+        let mut control_state = control_state.lock().unwrap();
+        let ctx = Context::default();
+        let mut text = String::new();
+        let mut frame = |events: Vec<egui::Event>, focus: bool| {
+            let _ = ctx.run(
+                egui::RawInput {
+                    events,
+                    ..Default::default()
+                },
+                |ctx| {
+                    egui::CentralPanel::default().show(ctx, |ui| {
+                        let response = ui.text_edit_singleline(&mut text);
+                        if focus {
+                            response.request_focus();
+                        }
+                    });
+                    process(ctx, &mut control_state);
+                },
+            );
+        };
+
+        frame(Vec::new(), true);
+        frame(
+            vec![
+                egui::Event::Key {
+                    key: Key::Space,
+                    physical_key: None,
+                    pressed: true,
+                    repeat: false,
+                    modifiers: Modifiers::NONE,
+                },
+                egui::Event::Text(" ".into()),
+            ],
+            false,
+        );
+
+        assert_eq!(text, " ");
+        assert_eq!(control_state.run_state, RunState::Stopped);
+    }
+}
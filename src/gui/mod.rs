@@ -1,23 +1,28 @@
 use crate::{
     ControlMessage, ControlState, DisplayMode, LINE_BUFFER_SIZE, Line,
-    RunState, xrandr::MonitorPositions,
+    MAX_REPLAY_SPEED, ProfanityMode, RunState, TranslationLayout, Utterance,
+    remote::{self, Caption},
+    xrandr::MonitorPositions,
 };
 use color_eyre::Result;
 use egui::{Modal, ViewportBuilder, ViewportCommand, ViewportId};
 use std::{
-    collections::VecDeque,
+    collections::{BTreeMap, VecDeque},
     ops::DerefMut,
     sync::{
         Arc, Mutex,
         atomic::{AtomicBool, Ordering},
     },
+    time::{Duration, Instant},
 };
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
 
 mod captions;
 mod controls;
 mod holding_image;
 mod input;
+pub mod moderation;
+pub mod signal;
 
 macro_rules! store {
     ($storage:ident,$control_state:ident, $($field:ident),* $(,)?) => {
@@ -44,9 +49,10 @@ macro_rules! load {
 }
 
 pub struct MyApp {
-    text_buffer: VecDeque<String>,
-    active_line: Option<String>,
+    text_buffer: VecDeque<Utterance>,
+    active_line: Option<Utterance>,
     rx: mpsc::Receiver<Line>,
+    captions_tx: broadcast::Sender<Caption>,
     monitor_positions: MonitorPositions,
     config: crate::config::Config,
     control_state: Arc<Mutex<ControlState>>,
@@ -55,8 +61,11 @@ pub struct MyApp {
 impl MyApp {
     pub async fn new(
         rx: mpsc::Receiver<Line>,
+        captions_tx: broadcast::Sender<Caption>,
         config: crate::config::Config,
         control_tx: mpsc::Sender<ControlMessage>,
+        audio_level: watch::Receiver<Option<crate::listener::AudioLevel>>,
+        listener_status: watch::Receiver<crate::listener::Status>,
         monitor_positions: crate::xrandr::MonitorPositions,
     ) -> Result<Self> {
         let wordlist = {
@@ -74,11 +83,25 @@ impl MyApp {
             .map(crate::list_directory)
             .unwrap_or_default();
         let selected_image = image_options.first().cloned();
+        let keybindings = config.keybindings.clone();
+        let language_options = config.languages.clone();
+        let language = language_options[0].clone();
+        let translation_options = config.translation_languages().to_vec();
+        let audio_config = config.audio.clone();
+        let signal_monitor = signal::Monitor::new(&config.audio);
+        let test_data_dir = config.test_data_dir.clone();
+        let (test_data_options, replay_options) = crate::list_replays(
+            config.test_data_dir.as_deref(),
+            config.audio.recordings_dir.as_deref(),
+        );
+        let audio_device_options =
+            crate::listener::list_audio_devices(&audio_config);
 
         Ok(Self {
             text_buffer: VecDeque::with_capacity(LINE_BUFFER_SIZE * 2),
             active_line: None,
             rx,
+            captions_tx,
             monitor_positions,
             config,
             control_state: Arc::new(Mutex::new(ControlState {
@@ -93,14 +116,47 @@ impl MyApp {
                 run_state: RunState::default(),
                 wordlist_options: wordlist.options,
                 wordlist: wordlist.current,
+                language_options,
+                language,
+                auto_detect_language: false,
+                translation_options,
+                translation: None,
+                translation_layout: TranslationLayout::default(),
+                profanity: ProfanityMode::default(),
+                audio_config,
+                audio_device_options,
+                audio_device: None,
+                replay_options,
+                replay: None,
+                replay_url: String::new(),
+                replay_speed: 1.0,
+                test_data_dir,
+                test_data_options,
+                test_data: None,
+                test_loop: true,
+                audio_level,
+                signal_monitor,
+                listener_status,
+                snapshots: watch::Sender::new(Default::default()),
+                highlight_low_confidence: false,
+                confidence_threshold: 0.5,
+                speaker_names: BTreeMap::new(),
+                moderation_enabled: false,
+                moderation_delay: 5.0,
+                moderation_queue: moderation::Queue::default(),
                 request_close: AtomicBool::default(),
                 request_clear: AtomicBool::default(),
                 image_options,
                 selected_image,
+                keybindings,
             })),
         })
     }
 
+    pub fn control_state(&self) -> Arc<Mutex<ControlState>> {
+        Arc::clone(&self.control_state)
+    }
+
     pub fn save_control_state(&self, storage: &mut dyn eframe::Storage) {
         let control_state = self.control_state.lock().unwrap();
         store!(
@@ -112,6 +168,20 @@ impl MyApp {
             display_mode,
             wordlist,
             selected_image,
+            language,
+            auto_detect_language,
+            translation,
+            translation_layout,
+            speaker_names,
+            profanity,
+            audio_device,
+            replay_speed,
+            test_data,
+            test_loop,
+            highlight_low_confidence,
+            confidence_threshold,
+            moderation_enabled,
+            moderation_delay,
         );
     }
 
@@ -126,7 +196,51 @@ impl MyApp {
             display_mode,
             wordlist,
             selected_image,
+            language,
+            auto_detect_language,
+            translation,
+            translation_layout,
+            speaker_names,
+            profanity,
+            audio_device,
+            replay_speed,
+            test_data,
+            test_loop,
+            highlight_low_confidence,
+            confidence_threshold,
+            moderation_enabled,
+            moderation_delay,
         );
+
+        control_state.replay_speed =
+            control_state.replay_speed.clamp(1.0, MAX_REPLAY_SPEED);
+        control_state.moderation_delay = control_state
+            .moderation_delay
+            .clamp(moderation::MIN_DELAY, moderation::MAX_DELAY);
+
+        // Tell the listener about the restored choices
+        if !control_state
+            .language_options
+            .contains(&control_state.language)
+        {
+            control_state.language = control_state.language_options[0].clone();
+        }
+        if let Some(test_data) = &control_state.test_data
+            && !control_state.test_data_options.contains(test_data)
+        {
+            control_state.test_data = None;
+        }
+        if let Some(translation) = &control_state.translation
+            && !control_state.translation_options.contains(translation)
+        {
+            control_state.translation = None;
+        }
+        control_state.update_language();
+        control_state.update_wordlist();
+        control_state.update_translation();
+        control_state.update_profanity();
+        control_state.update_audio_device();
+        control_state.update_test_data();
     }
 }
 
@@ -150,19 +264,95 @@ impl eframe::App for MyApp {
             },
         );
 
+        let mut control_state = self.control_state.lock().unwrap();
+        control_state.sync_listener_status();
+
+        let now = Instant::now();
+        let mut lines = Vec::new();
         while let Ok(line) = self.rx.try_recv() {
+            if let Line::Recognised(utterance) = &line
+                && let Some(speaker) = &utterance.speaker
+            {
+                control_state
+                    .speaker_names
+                    .entry(speaker.clone())
+                    .or_default();
+            }
+            // Lines still go through the queue once moderation is switched
+            // off until it empties, so that they don't overtake those held
+            // back
+            let queueing = control_state.moderation_enabled
+                || !control_state.moderation_queue.is_empty();
             match line {
-                Line::Recognising(line) => {
-                    self.active_line = Some(line);
+                // Partial text is not shown while moderating as nobody has
+                // had a chance to check it
+                Line::Recognising(_) if queueing => {
+                    self.active_line = None;
                 }
-                Line::Recognised(line) => {
-                    self.text_buffer.push_back(line);
-                    self.active_line.take();
+                Line::Recognised(utterance) if queueing => {
+                    let delay = if control_state.moderation_enabled {
+                        Duration::from_secs_f32(control_state.moderation_delay)
+                    } else {
+                        Duration::ZERO
+                    };
+                    control_state.moderation_queue.push(utterance, delay, now);
+                }
+                // Lines still held back take their translation with them
+                // when released
+                Line::Translated { id, text } => {
+                    match control_state
+                        .moderation_queue
+                        .iter_mut()
+                        .find(|pending| pending.utterance.id == Some(id))
+                    {
+                        Some(pending) => {
+                            pending.utterance.translation = Some(text);
+                        }
+                        None => lines.push(Line::Translated { id, text }),
+                    }
                 }
+                line => lines.push(line),
+            }
+        }
+        if !control_state.moderation_enabled {
+            control_state.moderation_queue.release_all(now);
+        }
+        lines.extend(
+            control_state
+                .moderation_queue
+                .release_due(now)
+                .into_iter()
+                .map(Line::Recognised),
+        );
+        for (id, text) in control_state.moderation_queue.take_corrections() {
+            let msg = ControlMessage::CorrectLine { id, text };
+            if let Err(err) = control_state.control_tx.try_send(msg) {
+                warn!("Failed to pass on a correction: {err}");
             }
         }
 
-        let mut control_state = self.control_state.lock().unwrap();
+        for line in lines {
+            // Only fails if there are no remote viewers
+            let _ = self.captions_tx.send(Caption::Line(line.clone()));
+            match line {
+                Line::Recognising(utterance) => {
+                    self.active_line = Some(utterance);
+                }
+                Line::Recognised(utterance) => {
+                    self.text_buffer.push_back(utterance);
+                    self.active_line.take();
+                }
+                Line::Translated { id, text } => {
+                    if let Some(utterance) = self
+                        .text_buffer
+                        .iter_mut()
+                        .find(|utterance| utterance.id == Some(id))
+                    {
+                        utterance.translation = Some(text);
+                    }
+                }
+            }
+        }
 
         if control_state.request_close.load(Ordering::Relaxed) {
             ctx.send_viewport_cmd(ViewportCommand::Close);
@@ -170,14 +360,12 @@ impl eframe::App for MyApp {
         if control_state.request_clear.swap(false, Ordering::Relaxed) {
             self.text_buffer.clear();
             self.active_line = None;
+            let _ = self.captions_tx.send(Caption::Clear);
         }
 
         input::process(ctx, control_state.deref_mut());
 
-        let limit = match control_state.display_mode {
-            DisplayMode::Fullscreen => LINE_BUFFER_SIZE,
-            DisplayMode::Subtitle => 4,
-        };
+        let limit = control_state.line_limit();
         while self.text_buffer.len() > limit {
             self.text_buffer.pop_front();
         }
@@ -187,6 +375,7 @@ impl eframe::App for MyApp {
                 .show(ctx, |ui| controls::show(ui, control_state.deref_mut()));
         }
 
+        remote::publish(&control_state);
         let run_state = control_state.run_state;
         let selected_image = control_state.selected_image.clone();
         drop(control_state);
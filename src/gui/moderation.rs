@@ -0,0 +1,225 @@
+//! Finished lines held back so that the operator can correct or drop them
+//! before they reach the screen
+
+use crate::Utterance;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// Range of the delay slider, in seconds
+pub const MIN_DELAY: f32 = 1.0;
+pub const MAX_DELAY: f32 = 30.0;
+/// How long a line waits after the operator last touched it
+const EDIT_GRACE: Duration = Duration::from_secs(3);
+/// The longest a line can be kept back by editing it, beyond its delay, so
+/// that an editor left with focus can't stop the captions
+const MAX_HOLD: Duration = Duration::from_secs(30);
+
+pub struct Pending {
+    pub utterance: Utterance,
+    /// Text as recognised, to tell whether the operator changed it
+    original: String,
+    release_at: Instant,
+    /// When the line is released however it is being edited
+    deadline: Instant,
+}
+
+impl Pending {
+    /// Time until the line is released automatically
+    pub fn remaining(&self, now: Instant) -> Duration {
+        self.release_at.saturating_duration_since(now)
+    }
+
+    /// Keep the line back a little longer while the operator works on it,
+    /// though no later than its deadline
+    pub fn hold(&mut self, now: Instant) {
+        self.release_at =
+            self.release_at.max(now + EDIT_GRACE).min(self.deadline);
+    }
+
+    pub fn release_now(&mut self, now: Instant) {
+        self.release_at = now;
+        self.deadline = now;
+    }
+}
+
+#[derive(Default)]
+pub struct Queue {
+    pending: VecDeque<Pending>,
+    /// Ids of lines which were edited or dropped, along with the text they
+    /// were shown with, for the transcript
+    corrections: Vec<(u64, Option<String>)>,
+}
+
+impl Queue {
+    pub fn push(
+        &mut self,
+        utterance: Utterance,
+        delay: Duration,
+        now: Instant,
+    ) {
+        self.pending.push_back(Pending {
+            original: utterance.text.clone(),
+            utterance,
+            release_at: now + delay,
+            deadline: now + delay + MAX_HOLD,
+        });
+    }
+
+    /// Remove and return the lines which are due, in the order they were
+    /// recognised. A line which is not yet due holds back the ones after it,
+    /// so that lines never overtake each other.
+    pub fn release_due(&mut self, now: Instant) -> Vec<Utterance> {
+        let mut released = Vec::new();
+        while let Some(pending) = self.pending.front()
+            && pending.release_at <= now
+        {
+            let Some(pending) = self.pending.pop_front() else {
+                break;
+            };
+            if let Some(id) = pending.utterance.id
+                && pending.utterance.text != pending.original
+            {
+                self.corrections
+                    .push((id, Some(pending.utterance.text.clone())));
+            }
+            released.push(pending.utterance);
+        }
+        released
+    }
+
+    /// Release everything on the next frame, e.g. when moderation is
+    /// switched off
+    pub fn release_all(&mut self, now: Instant) {
+        for pending in &mut self.pending {
+            pending.release_now(now);
+        }
+    }
+
+    pub fn remove(&mut self, index: usize) {
+        if let Some(pending) = self.pending.remove(index)
+            && let Some(id) = pending.utterance.id
+        {
+            self.corrections.push((id, None));
+        }
+    }
+
+    /// Lines edited or dropped since the last call, with `None` for those
+    /// which were dropped
+    pub fn take_corrections(&mut self) -> Vec<(u64, Option<String>)> {
+        std::mem::take(&mut self.corrections)
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Pending> {
+        self.pending.iter_mut()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const DELAY: Duration = Duration::from_secs(5);
+
+    fn texts(utterances: Vec<Utterance>) -> Vec<String> {
+        utterances
+            .into_iter()
+            .map(|utterance| utterance.text)
+            .collect()
+    }
+
+    #[test]
+    fn test_timeout_release() {
+        let start = Instant::now();
+        let mut queue = Queue::default();
+        queue.push("one".into(), DELAY, start);
+        queue.push("two".into(), DELAY, start + Duration::from_secs(1));
+
+        assert!(queue.release_due(start).is_empty());
+        assert_eq!(texts(queue.release_due(start + DELAY)), ["one"]);
+        assert_eq!(
+            texts(queue.release_due(start + Duration::from_secs(6))),
+            ["two"]
+        );
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_operator_actions() {
+        let start = Instant::now();
+        let mut queue = Queue::default();
+        for (id, text) in ["one", "two", "three"].into_iter().enumerate() {
+            let utterance = Utterance {
+                id: Some(id as u64),
+                ..text.into()
+            };
+            queue.push(utterance, DELAY, start);
+        }
+
+        // Edit the first line just before it is due, release the second
+        // early and drop the third
+        let editing = start + Duration::from_secs(4);
+        {
+            let mut pending = queue.iter_mut();
+            let first = pending.next().unwrap();
+            first.utterance.text = "won".into();
+            first.hold(editing);
+            pending.next().unwrap().release_now(editing);
+        }
+        queue.remove(2);
+
+        // The second line waits behind the first while it is being edited
+        assert!(queue.release_due(start + DELAY).is_empty());
+        assert_eq!(queue.take_corrections(), [(2, None)]);
+
+        let released = editing + EDIT_GRACE;
+        assert_eq!(texts(queue.release_due(released)), ["won", "two"]);
+        assert_eq!(queue.take_corrections(), [(0, Some("won".into()))]);
+        assert!(queue.take_corrections().is_empty());
+    }
+
+    #[test]
+    fn test_held_line_times_out() {
+        let start = Instant::now();
+        let mut queue = Queue::default();
+        queue.push("one".into(), DELAY, start);
+        queue.push("two".into(), DELAY, start + Duration::from_secs(1));
+
+        // An editor left with focus keeps holding the first line, with the
+        // second due behind it
+        let mut now = start;
+        while now < start + DELAY + MAX_HOLD {
+            queue.iter_mut().next().unwrap().hold(now);
+            assert!(queue.release_due(now).is_empty());
+            now += Duration::from_secs(1);
+        }
+
+        // Until its deadline, when both go in order
+        queue.iter_mut().next().unwrap().hold(now);
+        assert_eq!(texts(queue.release_due(now)), ["one", "two"]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_switch_off() {
+        let start = Instant::now();
+        let mut queue = Queue::default();
+        queue.push("one".into(), DELAY, start);
+        queue.push("two".into(), DELAY, start);
+        queue.iter_mut().next().unwrap().hold(start);
+
+        // Switching off releases the held back lines, and lines arriving
+        // meanwhile wait behind them
+        let now = start + Duration::from_secs(1);
+        queue.release_all(now);
+        queue.push("three".into(), Duration::ZERO, now);
+        assert_eq!(texts(queue.release_due(now)), ["one", "two", "three"]);
+        assert!(queue.is_empty());
+    }
+}
@@ -0,0 +1,147 @@
+//! Watch the input level so that a muted or overloaded channel is noticed
+//! before the captions stop
+
+use crate::{config::AudioConfig, listener::AudioLevel};
+use std::time::{Duration, Instant};
+
+/// Peak level in dBFS at which the input counts as clipping
+pub const CLIPPING_DB: f32 = -0.5;
+/// How long the input still counts as clipping after the last clipped frame,
+/// since a clipping signal only touches full scale on its loudest moments
+const CLIP_HOLD: Duration = Duration::from_secs(1);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Alarm {
+    Silent,
+    Clipping,
+}
+
+pub struct Monitor {
+    silence_threshold: f32,
+    period: Duration,
+    silent_since: Option<Instant>,
+    clipping_since: Option<Instant>,
+    last_clip: Option<Instant>,
+}
+
+impl Monitor {
+    pub fn new(config: &AudioConfig) -> Self {
+        Self {
+            silence_threshold: config.silence_threshold_db,
+            period: Duration::from_secs(config.alarm_after_secs),
+            silent_since: None,
+            clipping_since: None,
+            last_clip: None,
+        }
+    }
+
+    /// Track the latest level, which is `None` when nothing is being
+    /// captured
+    pub fn update(&mut self, level: Option<AudioLevel>, now: Instant) {
+        let Some(level) = level else {
+            self.silent_since = None;
+            self.clipping_since = None;
+            self.last_clip = None;
+            return;
+        };
+
+        if level.rms < self.silence_threshold {
+            self.silent_since.get_or_insert(now);
+        } else {
+            self.silent_since = None;
+        }
+
+        if level.peak >= CLIPPING_DB {
+            self.last_clip = Some(now);
+            self.clipping_since.get_or_insert(now);
+        } else if self
+            .last_clip
+            .is_none_or(|last_clip| now.duration_since(last_clip) > CLIP_HOLD)
+        {
+            self.clipping_since = None;
+        }
+    }
+
+    /// The problem the operator should be warned about, once it has lasted
+    /// the configured period
+    pub fn alarm(&self, now: Instant) -> Option<Alarm> {
+        let lasted = |since: Option<Instant>| {
+            since.is_some_and(|since| now.duration_since(since) >= self.period)
+        };
+        if lasted(self.silent_since) {
+            Some(Alarm::Silent)
+        } else if lasted(self.clipping_since) {
+            Some(Alarm::Clipping)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const SILENT: AudioLevel = AudioLevel {
+        peak: -70.0,
+        rms: -80.0,
+    };
+    const SPEECH: AudioLevel = AudioLevel {
+        peak: -6.0,
+        rms: -20.0,
+    };
+    const CLIPPED: AudioLevel = AudioLevel {
+        peak: 0.0,
+        rms: -8.0,
+    };
+
+    fn monitor() -> Monitor {
+        Monitor::new(&AudioConfig {
+            alarm_after_secs: 10,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_silence() {
+        let start = Instant::now();
+        let mut monitor = monitor();
+
+        monitor.update(Some(SILENT), start);
+        monitor.update(Some(SILENT), start + Duration::from_secs(9));
+        assert_eq!(monitor.alarm(start + Duration::from_secs(9)), None);
+        monitor.update(Some(SILENT), start + Duration::from_secs(10));
+        assert_eq!(
+            monitor.alarm(start + Duration::from_secs(10)),
+            Some(Alarm::Silent)
+        );
+
+        monitor.update(Some(SPEECH), start + Duration::from_secs(11));
+        assert_eq!(monitor.alarm(start + Duration::from_secs(11)), None);
+
+        // Stopping capture clears the alarm rather than counting as silence
+        monitor.update(Some(SILENT), start + Duration::from_secs(12));
+        monitor.update(None, start + Duration::from_secs(13));
+        assert_eq!(monitor.alarm(start + Duration::from_secs(30)), None);
+    }
+
+    #[test]
+    fn test_clipping() {
+        let start = Instant::now();
+        let mut monitor = monitor();
+
+        // Speech which keeps hitting full scale between quieter frames
+        for millis in (0..=10_000).step_by(100) {
+            let level = if millis % 500 == 0 { CLIPPED } else { SPEECH };
+            monitor.update(Some(level), start + Duration::from_millis(millis));
+        }
+        assert_eq!(
+            monitor.alarm(start + Duration::from_secs(10)),
+            Some(Alarm::Clipping)
+        );
+
+        monitor.update(Some(SPEECH), start + Duration::from_secs(12));
+        assert_eq!(monitor.alarm(start + Duration::from_secs(12)), None);
+    }
+}
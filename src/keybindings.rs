@@ -0,0 +1,372 @@
+use crate::Action;
+use color_eyre::{Report, Result, eyre::eyre};
+use egui::{InputState, Key, KeyboardShortcut, ModifierNames, Modifiers};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+const DEFAULT_BINDINGS: &[(&str, Action)] = &[
+    ("F1", Action::ShowControls),
+    ("Escape", Action::HideControls),
+    ("Minus", Action::FontSmaller),
+    ("Equals", Action::FontLarger),
+    ("ArrowUp", Action::SubtitleTaller),
+    ("ArrowDown", Action::SubtitleShorter),
+    ("D", Action::ToggleDarkMode),
+    ("M", Action::SwapDisplayMode),
+    ("Space", Action::ToggleRunning),
+    ("T", Action::ToggleTestMode),
+    ("H", Action::ToggleHoldingSlide),
+    ("F11", Action::ToggleFullscreen),
+];
+
+/// Mapping from keyboard shortcuts to actions. The `[keybindings]` table is
+/// merged over the defaults, with its entries replacing any default for the
+/// same shortcut, and `"none"` removing it.
+///
+/// ```toml
+/// [keybindings]
+/// F1 = "show_controls"
+/// "Ctrl+Space" = "toggle_running"
+/// Space = "none"
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "BTreeMap<String, Assignment>")]
+pub struct KeyBindings {
+    /// Configured bindings first, so that they are preferred for labels
+    bindings: Vec<Binding>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Binding {
+    shortcut: KeyboardShortcut,
+    action: Action,
+    /// Only trigger with exactly the shortcut's modifiers held. The defaults
+    /// trigger whatever modifiers are held, as the keys always have.
+    exact: bool,
+}
+
+/// What a shortcut is set to in the `[keybindings]` table
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Assignment {
+    /// Unbind the shortcut, e.g. to stop a clicker triggering a default
+    None,
+    #[serde(untagged)]
+    Action(Action),
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        Self {
+            bindings: DEFAULT_BINDINGS
+                .iter()
+                .map(|&(shortcut, action)| Binding {
+                    shortcut: parse_shortcut(shortcut).unwrap(),
+                    action,
+                    exact: false,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<BTreeMap<String, Assignment>> for KeyBindings {
+    type Error = Report;
+
+    fn try_from(table: BTreeMap<String, Assignment>) -> Result<Self> {
+        let mut assigned = Vec::<(KeyboardShortcut, Assignment)>::new();
+        for (shortcut, assignment) in table {
+            let parsed = parse_shortcut(&shortcut)?;
+            if let Some((_, other)) =
+                assigned.iter().find(|(existing, _)| *existing == parsed)
+            {
+                return Err(eyre!(
+                    "Key binding `{shortcut}` is assigned to both {other:?} \
+                    and {assignment:?}"
+                ));
+            }
+            assigned.push((parsed, assignment));
+        }
+
+        let defaults = Self::default()
+            .bindings
+            .into_iter()
+            .filter(|default| {
+                !assigned
+                    .iter()
+                    .any(|(shortcut, _)| *shortcut == default.shortcut)
+            })
+            .collect::<Vec<_>>();
+        let mut bindings = assigned
+            .into_iter()
+            .filter_map(|(shortcut, assignment)| match assignment {
+                Assignment::None => None,
+                Assignment::Action(action) => Some(Binding {
+                    shortcut,
+                    action,
+                    exact: true,
+                }),
+            })
+            .collect::<Vec<_>>();
+        bindings.extend(defaults);
+        Ok(Self { bindings })
+    }
+}
+
+impl KeyBindings {
+    /// Actions whose shortcuts were pressed this frame. A default binding
+    /// gives way to a configured one for the same key with other modifiers,
+    /// so that e.g. `Ctrl+Space` doesn't also trigger `Space`.
+    pub fn pressed(&self, input: &InputState) -> Vec<Action> {
+        let exact = self
+            .bindings
+            .iter()
+            .filter(|binding| {
+                binding.exact
+                    && input.modifiers.matches_exact(binding.shortcut.modifiers)
+                    && input.key_pressed(binding.shortcut.logical_key)
+            })
+            .collect::<Vec<_>>();
+        let loose = self.bindings.iter().filter(|binding| {
+            !binding.exact
+                && input.key_pressed(binding.shortcut.logical_key)
+                && !exact.iter().any(|matched| {
+                    matched.shortcut.logical_key == binding.shortcut.logical_key
+                })
+        });
+        exact
+            .iter()
+            .copied()
+            .chain(loose)
+            .map(|binding| binding.action)
+            .collect()
+    }
+
+    /// Human readable shortcut for an action, for labelling controls
+    pub fn label(&self, action: Action) -> Option<String> {
+        self.bindings
+            .iter()
+            .find(|binding| binding.action == action)
+            .map(|binding| format_shortcut(&binding.shortcut))
+    }
+
+    /// All bindings as human readable shortcuts
+    pub fn iter(&self) -> impl Iterator<Item = (String, Action)> + '_ {
+        self.bindings
+            .iter()
+            .map(|binding| (format_shortcut(&binding.shortcut), binding.action))
+    }
+}
+
+fn format_shortcut(shortcut: &KeyboardShortcut) -> String {
+    shortcut.format(&ModifierNames::NAMES, false)
+}
+
+/// Parse a shortcut such as `Ctrl+Shift+T`. Key names are those understood
+/// by [`Key::from_name`].
+fn parse_shortcut(shortcut: &str) -> Result<KeyboardShortcut> {
+    let (modifier_names, key_name) = match shortcut.rsplit_once('+') {
+        // Allow binding the plus key itself, e.g. `Ctrl++`
+        Some((rest, "")) => (rest.strip_suffix('+').unwrap_or(rest), "+"),
+        Some((rest, key)) => (rest, key),
+        None => ("", shortcut),
+    };
+
+    let mut modifiers = Modifiers::NONE;
+    for name in modifier_names.split('+').filter(|name| !name.is_empty()) {
+        modifiers |= match name.to_ascii_lowercase().as_str() {
+            "ctrl" | "control" => Modifiers::CTRL,
+            "shift" => Modifiers::SHIFT,
+            "alt" => Modifiers::ALT,
+            "cmd" | "command" => Modifiers::COMMAND,
+            _ => {
+                return Err(eyre!(
+                    "Unknown modifier `{name}` in key binding `{shortcut}`"
+                ));
+            }
+        };
+    }
+
+    let key = Key::from_name(key_name).ok_or_else(|| {
+        eyre!("Unknown key `{key_name}` in key binding `{shortcut}`")
+    })?;
+
+    Ok(KeyboardShortcut::new(modifiers, key))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn parse(table: &str) -> Result<KeyBindings> {
+        toml::de::from_str::<BTreeMap<String, Assignment>>(table)?.try_into()
+    }
+
+    #[test]
+    fn test_parse_shortcut() {
+        assert_eq!(
+            parse_shortcut("Space").unwrap(),
+            KeyboardShortcut::new(Modifiers::NONE, Key::Space)
+        );
+        assert_eq!(
+            parse_shortcut("Ctrl+Shift+T").unwrap(),
+            KeyboardShortcut::new(Modifiers::CTRL | Modifiers::SHIFT, Key::T)
+        );
+        assert_eq!(
+            parse_shortcut("alt+PageDown").unwrap(),
+            KeyboardShortcut::new(Modifiers::ALT, Key::PageDown)
+        );
+        assert_eq!(
+            parse_shortcut("Ctrl++").unwrap(),
+            KeyboardShortcut::new(Modifiers::CTRL, Key::Plus)
+        );
+        assert!(parse_shortcut("Hyper+T").is_err());
+        assert!(parse_shortcut("Ctrl+NotAKey").is_err());
+    }
+
+    /// Actions triggered by pressing `key` with `modifiers` held
+    fn press(
+        bindings: &KeyBindings,
+        modifiers: Modifiers,
+        key: Key,
+    ) -> Vec<Action> {
+        let ctx = egui::Context::default();
+        let mut actions = Vec::new();
+        let _ = ctx.run(
+            egui::RawInput {
+                modifiers,
+                events: vec![egui::Event::Key {
+                    key,
+                    physical_key: None,
+                    pressed: true,
+                    repeat: false,
+                    modifiers,
+                }],
+                ..Default::default()
+            },
+            |ctx| actions = ctx.input(|input| bindings.pressed(input)),
+        );
+        actions
+    }
+
+    #[test]
+    fn test_defaults() {
+        let bindings = KeyBindings::default();
+        assert_eq!(bindings.bindings.len(), DEFAULT_BINDINGS.len());
+        assert_eq!(
+            bindings.label(Action::ToggleRunning).as_deref(),
+            Some("Space")
+        );
+    }
+
+    #[test]
+    fn test_from_table() {
+        let bindings = parse(
+            r#"
+            PageDown = "toggle_running"
+            "Ctrl+B" = "toggle_holding_slide"
+            H = "clear"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            bindings.bindings[..3],
+            [
+                Binding {
+                    shortcut: KeyboardShortcut::new(Modifiers::CTRL, Key::B),
+                    action: Action::ToggleHoldingSlide,
+                    exact: true,
+                },
+                Binding {
+                    shortcut: KeyboardShortcut::new(Modifiers::NONE, Key::H),
+                    action: Action::Clear,
+                    exact: true,
+                },
+                Binding {
+                    shortcut: KeyboardShortcut::new(
+                        Modifiers::NONE,
+                        Key::PageDown
+                    ),
+                    action: Action::ToggleRunning,
+                    exact: true,
+                },
+            ]
+        );
+        // The rest of the defaults are kept, apart from the one for `H`
+        assert_eq!(bindings.bindings.len(), DEFAULT_BINDINGS.len() + 2);
+        assert_eq!(bindings.label(Action::ShowControls).as_deref(), Some("F1"));
+        assert_eq!(
+            bindings.label(Action::ToggleRunning).as_deref(),
+            Some("PageDown")
+        );
+        assert_eq!(
+            bindings.label(Action::ToggleHoldingSlide).as_deref(),
+            Some("Ctrl+B")
+        );
+
+        assert!(parse(r#"PageDown = "not_an_action""#).is_err());
+        assert!(parse("Q = \"stop\"\nq = \"clear\"").is_err());
+    }
+
+    #[test]
+    fn test_pressed() {
+        let bindings = parse(
+            r#"
+            "Ctrl+Space" = "toggle_test_mode"
+            PageDown = "stop"
+            "#,
+        )
+        .unwrap();
+
+        // Defaults ignore modifiers
+        assert_eq!(
+            press(&bindings, Modifiers::SHIFT, Key::ArrowUp),
+            [Action::SubtitleTaller]
+        );
+        assert_eq!(
+            press(&bindings, Modifiers::NONE, Key::Space),
+            [Action::ToggleRunning]
+        );
+        // Configured bindings need exactly their modifiers, and take the key
+        // from the defaults when they match
+        assert_eq!(
+            press(&bindings, Modifiers::CTRL, Key::Space),
+            [Action::ToggleTestMode]
+        );
+        assert_eq!(
+            press(&bindings, Modifiers::NONE, Key::PageDown),
+            [Action::Stop]
+        );
+        assert_eq!(press(&bindings, Modifiers::SHIFT, Key::PageDown), []);
+    }
+
+    #[test]
+    fn test_unbind() {
+        let bindings = parse(
+            r#"
+            Space = "none"
+            ArrowUp = "none"
+            PageDown = "toggle_running"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(bindings.bindings.len(), DEFAULT_BINDINGS.len() - 1);
+        assert_eq!(press(&bindings, Modifiers::NONE, Key::Space), []);
+        assert_eq!(press(&bindings, Modifiers::NONE, Key::ArrowUp), []);
+        assert_eq!(bindings.label(Action::SubtitleTaller), None);
+        assert_eq!(
+            press(&bindings, Modifiers::NONE, Key::PageDown),
+            [Action::ToggleRunning]
+        );
+        // Other defaults are untouched
+        assert_eq!(
+            press(&bindings, Modifiers::NONE, Key::T),
+            [Action::ToggleTestMode]
+        );
+
+        assert!(parse("Q = \"none\"\nq = \"clear\"").is_err());
+    }
+}
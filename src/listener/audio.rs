@@ -0,0 +1,618 @@
+//! Audio capture, from the source chosen in the config or the device picker,
+//! or from a recording being replayed
+
+use super::SetupState;
+use crate::{
+    Replay, Result,
+    config::{AudioBackend, AudioConfig, Capture},
+    transcript::file_timestamp,
+};
+use std::{path::Path, process::Stdio, sync::Arc, time::SystemTime};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    sync::{mpsc, watch},
+};
+use tokio_stream::{Stream, StreamExt, wrappers::ReceiverStream};
+
+/// ffmpeg filters measuring each frame after any channel selection and gain,
+/// logged to stderr as `lavfi.astats.Overall.Peak_level=-12.3` and so on
+const METER_FILTERS: &str = "astats=metadata=1:reset=1:measure_perchannel=none\
+    :measure_overall=Peak_level+RMS_level,ametadata=print";
+const PEAK_KEY: &str = "lavfi.astats.Overall.Peak_level=";
+const RMS_KEY: &str = "lavfi.astats.Overall.RMS_level=";
+
+/// Level of the most recently captured audio, in dBFS
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct AudioLevel {
+    pub peak: f32,
+    pub rms: f32,
+}
+
+/// Encoding of the audio stream produced by ffmpeg
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AudioEncoding {
+    /// WebM container with Opus audio
+    WebmOpus,
+    /// Headerless 16 kHz mono signed 16-bit little-endian PCM
+    #[cfg_attr(not(feature = "vosk"), allow(dead_code))]
+    Pcm16kMono,
+    /// The same PCM preceded by a WAV header
+    Wav16kMono,
+}
+
+impl AudioEncoding {
+    /// Extension for a recording of the stream
+    const fn extension(self) -> &'static str {
+        match self {
+            Self::WebmOpus => "webm",
+            // Headerless PCM is recorded with a header so that it can be
+            // played back
+            Self::Pcm16kMono | Self::Wav16kMono => "wav",
+        }
+    }
+
+    /// Bytes of audio per second, for the uncompressed encodings
+    pub const fn byte_rate(self) -> Option<u32> {
+        match self {
+            Self::WebmOpus => None,
+            Self::Pcm16kMono | Self::Wav16kMono => Some(32_000),
+        }
+    }
+
+    const fn ffmpeg_args(self) -> &'static [&'static str] {
+        match self {
+            Self::WebmOpus => &["-f", "webm"],
+            Self::Pcm16kMono => &["-ac", "1", "-ar", "16000", "-f", "s16le"],
+            Self::Wav16kMono => &["-ac", "1", "-ar", "16000", "-f", "wav"],
+        }
+    }
+}
+
+impl AudioBackend {
+    /// ffmpeg input format for the backend
+    const fn ffmpeg_format(self) -> &'static str {
+        match self {
+            // PipeWire is captured through its PulseAudio compatible server
+            Self::Pulse | Self::Pipewire => "pulse",
+            Self::Alsa => "alsa",
+            Self::Jack => "jack",
+        }
+    }
+}
+
+/// Arguments selecting and adjusting the input, e.g.
+/// `-f pulse -ac 2 -i default -af volume=3dB,astats=...`
+fn ffmpeg_input_args(
+    config: &AudioConfig,
+    device: Option<&str>,
+) -> Vec<String> {
+    let device = device.or(config.device.as_deref()).unwrap_or("default");
+    let mut args = vec![
+        "-f".to_string(),
+        config.backend.ffmpeg_format().to_string(),
+        "-ac".to_string(),
+        config.channels.to_string(),
+        "-i".to_string(),
+        device.to_string(),
+    ];
+
+    let mut filters = Vec::new();
+    if let Some(channel) = config.channel {
+        // Channels are counted from 1 in the config, as on a mixer
+        filters.push(format!("pan=mono|c0=c{}", channel.saturating_sub(1)));
+    }
+    if config.gain_db != 0.0 {
+        filters.push(format!("volume={}dB", config.gain_db));
+    }
+    filters.push(METER_FILTERS.to_string());
+    args.push("-af".to_string());
+    args.push(filters.join(","));
+
+    args
+}
+
+/// Arguments reading a recording at the chosen speed, e.g.
+/// `-readrate 2 -i service.webm -af astats=...`
+fn replay_input_args(replay: &Replay) -> Vec<String> {
+    vec![
+        "-readrate".to_string(),
+        replay.speed.to_string(),
+        "-i".to_string(),
+        replay.source.to_string(),
+        "-af".to_string(),
+        METER_FILTERS.to_string(),
+    ]
+}
+
+/// Start capturing, publishing the input level to the setup's `levels` as it
+/// goes. Capture stops once the returned stream is dropped.
+pub async fn capture(
+    config: &AudioConfig,
+    setup_state: &SetupState,
+    encoding: AudioEncoding,
+) -> Result<impl Stream<Item = Vec<u8>> + use<>> {
+    let device = setup_state.audio_device.as_deref();
+    let levels = setup_state.levels.clone();
+    // Recordings are decoded by ffmpeg however live audio is captured
+    if let Some(replay) = &setup_state.replay {
+        let args = replay_input_args(replay);
+        return capture_ffmpeg(args, encoding, levels).await;
+    }
+
+    let stream = match config.capture {
+        Capture::Ffmpeg => {
+            let args = ffmpeg_input_args(config, device);
+            capture_ffmpeg(args, encoding, levels).await
+        }
+        #[cfg(feature = "native-audio")]
+        Capture::Native => {
+            super::native_audio::capture(config, device, encoding, levels).await
+        }
+        #[cfg(not(feature = "native-audio"))]
+        Capture::Native => Err(color_eyre::eyre::eyre!(
+            "Native audio capture requires building with \
+            `--features native-audio`"
+        )),
+    }?;
+
+    match &config.recordings_dir {
+        Some(recordings_dir) if config.record => {
+            let name = setup_state.session_name.clone().unwrap_or_else(|| {
+                format!("session-{}", file_timestamp(SystemTime::now()))
+            });
+            Ok(record(stream, recordings_dir, &name, encoding).await)
+        }
+        _ => Ok(stream),
+    }
+}
+
+/// Save a copy of the audio into `dir` as it is passed on, creating `dir` if
+/// need be. The audio is passed on regardless if the recording can't be
+/// started.
+///
+/// The recording is called `name`, numbered from `-2` for captures after the
+/// first in a session, e.g. after reconnecting.
+async fn record(
+    mut stream: ReceiverStream<Vec<u8>>,
+    dir: &Path,
+    name: &str,
+    encoding: AudioEncoding,
+) -> ReceiverStream<Vec<u8>> {
+    let path = (1..)
+        .map(|n| match n {
+            1 => dir.join(format!("{name}.{}", encoding.extension())),
+            n => dir.join(format!("{name}-{n}.{}", encoding.extension())),
+        })
+        .find(|path| !path.exists())
+        .expect("endless names");
+    let file = async {
+        tokio::fs::create_dir_all(dir).await?;
+        let mut file = tokio::fs::File::create(&path).await?;
+        if encoding == AudioEncoding::Pcm16kMono {
+            file.write_all(&wav_header()).await?;
+        }
+        std::io::Result::Ok(file)
+    };
+    let mut file = match file.await {
+        Ok(file) => file,
+        Err(err) => {
+            error!("Unable to record to {}: {err}", path.display());
+            return stream;
+        }
+    };
+    info!("Recording audio to {}", path.display());
+
+    let (tx, rx) = mpsc::channel(10);
+    tokio::task::spawn(async move {
+        let mut failed = false;
+        while let Some(chunk) = stream.next().await {
+            if !failed && let Err(err) = file.write_all(&chunk).await {
+                error!("Unable to record to {}: {err}", path.display());
+                failed = true;
+            }
+            if tx.send(chunk).await.is_err() {
+                break;
+            }
+        }
+        if let Err(err) = file.flush().await {
+            error!("Unable to record to {}: {err}", path.display());
+        }
+    });
+
+    ReceiverStream::new(rx)
+}
+
+// ffmpeg -y -hide_banner -nostats -f pulse -ac 2 -i default -af astats=... \
+//     -f webm /dev/stdout
+async fn capture_ffmpeg(
+    input_args: Vec<String>,
+    encoding: AudioEncoding,
+    levels: watch::Sender<Option<AudioLevel>>,
+) -> Result<ReceiverStream<Vec<u8>>> {
+    let (tx, rx) = mpsc::channel(10);
+
+    let mut child = tokio::process::Command::new("ffmpeg")
+        .args(["-y", "-hide_banner", "-nostats"])
+        .args(input_args)
+        .args(encoding.ffmpeg_args())
+        .arg("/dev/stdout")
+        .stderr(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let mut stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+
+    tokio::task::spawn(async move {
+        let mut lines = BufReader::new(stderr).lines();
+        let mut level = AudioLevel::default();
+        loop {
+            match lines.next_line().await {
+                Ok(Some(line)) => {
+                    if parse_level_line(&line, &mut level) {
+                        levels.send_replace(Some(level));
+                    }
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    warn!("Unable to read ffmpeg output: {err}");
+                    break;
+                }
+            }
+        }
+        // Nothing more will be measured once ffmpeg has exited
+        levels.send_replace(None);
+    });
+
+    // The child is owned by the reader so that ffmpeg is killed as soon as
+    // the session stops reading, rather than outliving a reconnection
+    tokio::task::spawn(async move {
+        let mut buf = [0; 4096];
+        loop {
+            let read = tokio::select! {
+                read = stdout.read(&mut buf) => read,
+                () = tx.closed() => {
+                    info!("Stream closed");
+                    break;
+                }
+            };
+            match read {
+                Ok(0) => {
+                    warn!("ffmpeg stream ended");
+                    break;
+                }
+                // Forward whatever has been read, so that the end of the
+                // stream is not lost waiting for a full buffer
+                Ok(len) => {
+                    if tx.send(buf[..len].to_vec()).await.is_err() {
+                        info!("Stream closed");
+                        break;
+                    }
+                }
+                Err(err) => {
+                    error!("Unable to read ffmpeg stream: {err}");
+                    break;
+                }
+            }
+        }
+        drop(child);
+    });
+
+    Ok(ReceiverStream::new(rx))
+}
+
+/// Header for a WAV stream of unknown length, which is marked with the
+/// largest possible sizes as ffmpeg does when writing to a pipe
+pub fn wav_header() -> Vec<u8> {
+    const SAMPLE_RATE: u32 = 16_000;
+    const CHANNELS: u16 = 1;
+    const BITS_PER_SAMPLE: u16 = 16;
+    const BLOCK_ALIGN: u16 = CHANNELS * BITS_PER_SAMPLE / 8;
+
+    let mut header = Vec::with_capacity(44);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&u32::MAX.to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&16_u32.to_le_bytes());
+    // Integer PCM
+    header.extend_from_slice(&1_u16.to_le_bytes());
+    header.extend_from_slice(&CHANNELS.to_le_bytes());
+    header.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    header.extend_from_slice(
+        &(SAMPLE_RATE * u32::from(BLOCK_ALIGN)).to_le_bytes(),
+    );
+    header.extend_from_slice(&BLOCK_ALIGN.to_le_bytes());
+    header.extend_from_slice(&BITS_PER_SAMPLE.to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&u32::MAX.to_le_bytes());
+    header
+}
+
+/// Update `level` from a line of ffmpeg's log, returning whether the line was
+/// the last measurement for its frame
+fn parse_level_line(line: &str, level: &mut AudioLevel) -> bool {
+    let value = |key: &str| {
+        let (_, value) = line.split_once(key)?;
+        // Digital silence is logged as `-inf`, which parses as is
+        value.trim().parse::<f32>().ok()
+    };
+    if let Some(peak) = value(PEAK_KEY) {
+        level.peak = peak;
+        false
+    } else if let Some(rms) = value(RMS_KEY) {
+        level.rms = rms;
+        true
+    } else {
+        false
+    }
+}
+
+/// Capture devices offered for the device picker
+pub fn list_devices(config: &AudioConfig) -> Vec<Arc<str>> {
+    match config.capture {
+        Capture::Ffmpeg => list_ffmpeg_devices(config.backend),
+        #[cfg(feature = "native-audio")]
+        Capture::Native => super::native_audio::list_devices(),
+        #[cfg(not(feature = "native-audio"))]
+        Capture::Native => Vec::new(),
+    }
+}
+
+/// Turns the output of a device listing command into device names
+type ParseDevices = fn(&str) -> Vec<Arc<str>>;
+
+fn list_ffmpeg_devices(backend: AudioBackend) -> Vec<Arc<str>> {
+    let (program, args, parse): (_, &[&str], ParseDevices) = match backend {
+        AudioBackend::Pulse | AudioBackend::Pipewire => {
+            ("pactl", &["list", "short", "sources"], parse_pactl_sources)
+        }
+        AudioBackend::Alsa => ("arecord", &["-L"], parse_arecord_devices),
+        // JACK ports are connected with the JACK tools rather than by
+        // choosing a device
+        AudioBackend::Jack => return Vec::new(),
+    };
+
+    match std::process::Command::new(program).args(args).output() {
+        Ok(output) if output.status.success() => {
+            parse(&String::from_utf8_lossy(&output.stdout))
+        }
+        Ok(output) => {
+            warn!(
+                "{program} failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+            Vec::new()
+        }
+        Err(err) => {
+            warn!("Unable to run {program}: {err}");
+            Vec::new()
+        }
+    }
+}
+
+/// Source names from `pactl list short sources`, which prints one
+/// tab-separated source per line with the name in the second column
+fn parse_pactl_sources(output: &str) -> Vec<Arc<str>> {
+    output
+        .lines()
+        .filter_map(|line| line.split('\t').nth(1))
+        .map(Into::into)
+        .collect()
+}
+
+/// Device names from `arecord -L`, which prints each name unindented
+/// followed by indented descriptions
+fn parse_arecord_devices(output: &str) -> Vec<Arc<str>> {
+    output
+        .lines()
+        .filter(|line| {
+            !line.is_empty() && !line.starts_with(char::is_whitespace)
+        })
+        .map(Into::into)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_ffmpeg_input_args() {
+        let mut config = AudioConfig::default();
+        assert_eq!(
+            ffmpeg_input_args(&config, None),
+            [
+                "-f",
+                "pulse",
+                "-ac",
+                "2",
+                "-i",
+                "default",
+                "-af",
+                METER_FILTERS
+            ]
+        );
+
+        config.backend = AudioBackend::Alsa;
+        config.device = Some("hw:CARD=Mixer".into());
+        config.channels = 8;
+        config.channel = Some(3);
+        config.gain_db = -6.0;
+        assert_eq!(
+            ffmpeg_input_args(&config, None),
+            [
+                "-f",
+                "alsa",
+                "-ac",
+                "8",
+                "-i",
+                "hw:CARD=Mixer",
+                "-af",
+                &format!("pan=mono|c0=c2,volume=-6dB,{METER_FILTERS}"),
+            ]
+        );
+
+        // The device picker overrides the config
+        assert_eq!(ffmpeg_input_args(&config, Some("default"))[5], "default");
+    }
+
+    #[test]
+    fn test_replay_input_args() {
+        let replay = Replay {
+            source: "https://radio.example.org/live.ogg".into(),
+            speed: 1.5,
+        };
+        assert_eq!(
+            replay_input_args(&replay),
+            [
+                "-readrate",
+                "1.5",
+                "-i",
+                "https://radio.example.org/live.ogg",
+                "-af",
+                METER_FILTERS
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_record() {
+        let dir = std::env::temp_dir()
+            .join(format!("captioninator-audio-{}", std::process::id()));
+        let audio = |chunks: &[&[u8]]| {
+            ReceiverStream::new({
+                let (tx, rx) = mpsc::channel(chunks.len());
+                for chunk in chunks {
+                    tx.try_send(chunk.to_vec()).unwrap();
+                }
+                rx
+            })
+        };
+
+        // The directory is created on the first recording
+        let recordings_dir = dir.join("recordings");
+        let passed_on = record(
+            audio(&[b"one", b"two"]),
+            &recordings_dir,
+            "session",
+            AudioEncoding::Pcm16kMono,
+        )
+        .await
+        .collect::<Vec<_>>()
+        .await;
+        assert_eq!(passed_on, [b"one", b"two"]);
+        let recorded =
+            std::fs::read(recordings_dir.join("session.wav")).unwrap();
+        assert_eq!(recorded[44..], *b"onetwo");
+
+        // A capture after reconnecting doesn't overwrite the first
+        record(
+            audio(&[b"again"]),
+            &recordings_dir,
+            "session",
+            AudioEncoding::Pcm16kMono,
+        )
+        .await
+        .collect::<Vec<_>>()
+        .await;
+        let recorded =
+            std::fs::read(recordings_dir.join("session-2.wav")).unwrap();
+        assert_eq!(recorded[44..], *b"again");
+
+        // Audio still gets through if the recording can't be made
+        let blocked = dir.join("file");
+        std::fs::write(&blocked, "").unwrap();
+        let passed_on = record(
+            audio(&[b"three"]),
+            &blocked,
+            "session",
+            AudioEncoding::WebmOpus,
+        )
+        .await
+        .collect::<Vec<_>>()
+        .await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(passed_on, [b"three"]);
+    }
+
+    #[test]
+    fn test_wav_header() {
+        let header = wav_header();
+        assert_eq!(header.len(), 44);
+        assert_eq!(&header[24..28], &16_000_u32.to_le_bytes());
+        assert_eq!(&header[28..32], &32_000_u32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_parse_level_line() {
+        let mut level = AudioLevel::default();
+        for (line, complete) in [
+            ("[Parsed_ametadata_1 @ 0x5581] frame:42 pts:43008", false),
+            (
+                "[Parsed_ametadata_1 @ 0x5581] \
+                lavfi.astats.Overall.Peak_level=-3.521",
+                false,
+            ),
+            (
+                "[Parsed_ametadata_1 @ 0x5581] \
+                lavfi.astats.Overall.RMS_level=-18.250",
+                true,
+            ),
+        ] {
+            assert_eq!(parse_level_line(line, &mut level), complete);
+        }
+        assert_eq!(
+            level,
+            AudioLevel {
+                peak: -3.521,
+                rms: -18.25
+            }
+        );
+
+        parse_level_line(
+            "[Parsed_ametadata_1 @ 0x5581] \
+            lavfi.astats.Overall.RMS_level=-inf",
+            &mut level,
+        );
+        assert_eq!(level.rms, f32::NEG_INFINITY);
+    }
+
+    #[test]
+    fn test_parse_pactl_sources() {
+        let output = "\
+45\talsa_output.pci-0000_00_1f.3.analog-stereo.monitor\tPipeWire\ts32le 2ch 48000Hz\tSUSPENDED
+46\talsa_input.usb-Mixer_USB_Audio-00.multichannel-input\tPipeWire\ts32le 8ch 48000Hz\tRUNNING
+";
+        assert_eq!(
+            parse_pactl_sources(output),
+            [
+                Arc::from("alsa_output.pci-0000_00_1f.3.analog-stereo.monitor"),
+                Arc::from(
+                    "alsa_input.usb-Mixer_USB_Audio-00.multichannel-input"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_arecord_devices() {
+        let output = "\
+null
+    Discard all samples (playback) or generate zero samples (capture)
+default
+    Default ALSA Output (currently PipeWire Media Server)
+hw:CARD=Mixer,DEV=0
+    USB Mixer, USB Audio
+    Direct hardware device without any conversions
+";
+        assert_eq!(
+            parse_arecord_devices(output),
+            [
+                Arc::from("null"),
+                Arc::from("default"),
+                Arc::from("hw:CARD=Mixer,DEV=0"),
+            ]
+        );
+    }
+}
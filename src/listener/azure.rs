@@ -0,0 +1,1152 @@
+use super::{
+    AuthError, RecognitionBackend, SetupState,
+    audio::{self, AudioEncoding},
+};
+use crate::{
+    Alternative, Line, ProfanityMode, Result, Utterance, Word,
+    config::{Capture, Config},
+    transcript::iso_timestamp,
+};
+use azure_speech::{
+    Data, StreamExt as _, make_binary_payload, make_text_payload, recognizer,
+};
+use color_eyre::{Report, eyre::eyre};
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::sync::mpsc;
+use tokio_stream::{Stream, StreamExt};
+use tokio_websockets::Message;
+
+/// Size of the pieces the audio is sent to the service in
+const AUDIO_CHUNK_SIZE: usize = 4096;
+
+pub(super) struct AzureBackend {
+    service: Service,
+}
+
+/// Where the recogniser connects to
+enum Service {
+    /// Azure's endpoint for the resource's region
+    Region(azure_speech::Auth),
+    /// A Speech container or another host speaking the same protocol
+    Endpoint { url: String, key: Option<String> },
+}
+
+impl AzureBackend {
+    pub fn new(config: &Config) -> Result<Self> {
+        let service =
+            match (&config.speech_endpoint, &config.region, &config.key) {
+                (Some(url), _, key) => Service::Endpoint {
+                    url: url.clone(),
+                    key: key.clone(),
+                },
+                (None, Some(region), Some(key)) => Service::Region(
+                    azure_speech::Auth::from_subscription(region, key),
+                ),
+                _ => {
+                    return Err(eyre!(
+                        "Region and key are required for Azure listener"
+                    ));
+                }
+            };
+        Ok(Self { service })
+    }
+
+    /// Connect a client set up for the current settings, along with what to
+    /// tell the service at the start of each turn
+    async fn open(
+        &self,
+        setup_state: &SetupState,
+        config: &Config,
+    ) -> Result<(recognizer::Client, Setup)> {
+        let mut azure_config = recognizer::Config::default()
+            .set_language(language_from_locale(&setup_state.language))
+            .set_profanity(match setup_state.profanity {
+                ProfanityMode::Raw => recognizer::Profanity::Raw,
+                ProfanityMode::Masked => recognizer::Profanity::Masked,
+                ProfanityMode::Removed => recognizer::Profanity::Removed,
+            });
+        // Needed for confidence and alternatives. The client also asks for
+        // word-level timestamps with this format.
+        azure_config =
+            azure_config.set_output_format(recognizer::OutputFormat::Detailed);
+
+        // Detected languages are reported back as locales, so keep the
+        // configured ones to match them against
+        let candidates = if setup_state.auto_detect {
+            config.detect_languages().to_vec()
+        } else {
+            Vec::new()
+        };
+        if !candidates.is_empty() {
+            azure_config = azure_config.set_detect_languages(
+                candidates
+                    .iter()
+                    .map(|locale| language_from_locale(locale))
+                    .collect(),
+                recognizer::LanguageDetectMode::Continuous,
+            );
+        }
+
+        let phrases = match (&config.wordlist_dir, &setup_state.wordlist) {
+            (Some(wordlist_dir), Some(wordlist_file)) => {
+                let wordlist_path = wordlist_dir.join(wordlist_file.as_ref());
+                std::fs::read_to_string(wordlist_path)?
+                    .lines()
+                    .filter(|line| !line.is_empty())
+                    .map(String::from)
+                    .collect()
+            }
+            _ => Vec::new(),
+        };
+
+        let client = match &self.service {
+            Service::Region(auth) => {
+                recognizer::Client::connect(auth.clone(), azure_config)
+                    .await
+                    .map_err(handshake_error)?
+            }
+            Service::Endpoint { url, key } => {
+                let uri = endpoint_uri(url, setup_state, &candidates);
+                let mut builder =
+                    tokio_websockets::ClientBuilder::new().uri(&uri)?;
+                if let Some(key) = key {
+                    builder = builder.add_header(
+                        "Ocp-Apim-Subscription-Key".try_into()?,
+                        key.as_str().try_into()?,
+                    )?;
+                }
+                let connection = azure_speech::Client::connect(builder)
+                    .await
+                    .map_err(handshake_error)?;
+                recognizer::Client::new(connection, azure_config)
+            }
+        };
+        let setup = Setup {
+            context: speech_context(&phrases, &candidates),
+            candidates,
+        };
+        Ok((client, setup))
+    }
+}
+
+/// The address `recognizer::Client::connect` would use for the region, on
+/// another host
+fn endpoint_uri(
+    url: &str,
+    setup_state: &SetupState,
+    candidates: &[Arc<str>],
+) -> String {
+    let language = candidates.first().unwrap_or(&setup_state.language);
+    let profanity = match setup_state.profanity {
+        ProfanityMode::Raw => "raw",
+        ProfanityMode::Masked => "masked",
+        ProfanityMode::Removed => "removed",
+    };
+    let mut uri = format!(
+        "{}/speech/recognition/conversation/cognitiveservices/v1\
+         ?language={language}&format=detailed&profanity={profanity}\
+         &wordLevelTimestamps=true",
+        url.trim_end_matches('/'),
+    );
+    if candidates.len() > 1 {
+        uri.push_str("&lidEnabled=true");
+    }
+    uri
+}
+
+/// What the service is told at the start of each turn
+struct Setup {
+    /// Body of the `speech.context` message
+    context: serde_json::Value,
+    /// Locales which may be detected
+    candidates: Vec<Arc<str>>,
+}
+
+/// Describes the client, once per connection
+fn speech_config() -> serde_json::Value {
+    json!({
+        "context": {
+            "system": {
+                "name": env!("CARGO_PKG_NAME"),
+                "version": env!("CARGO_PKG_VERSION"),
+                "build": "Rust",
+                "lang": "Rust",
+            },
+            "os": {
+                "platform": std::env::consts::OS,
+                "name": std::env::consts::OS,
+                "version": "Unknown",
+            },
+            "audio": {
+                "source": {
+                    "connectivity": "Unknown",
+                    "manufacturer": "Unknown",
+                    "model": "Unknown",
+                    "type": "Microphones",
+                },
+            },
+        },
+        "recognition": "conversation",
+    })
+}
+
+/// Turns on speaker diarization, which labels each phrase with a
+/// `SpeakerId`, along with the wordlist's phrases and language detection
+/// as `recognizer::Client` would set them up
+fn speech_context(
+    phrases: &[String],
+    candidates: &[Arc<str>],
+) -> serde_json::Value {
+    let mut context = json!({
+        "phraseDetection": {
+            "mode": "Conversation",
+            "speakerDiarization": {
+                "mode": "Anonymous",
+                "audioSessionId": uuid::Uuid::new_v4().simple().to_string(),
+                "audioOffsetMs": 0,
+            },
+        },
+    });
+    if !phrases.is_empty() {
+        let items = phrases
+            .iter()
+            .map(|text| json!({ "Text": text }))
+            .collect::<Vec<_>>();
+        context["dgi"] = json!({
+            "Groups": [{ "Type": "Generic", "Items": items }],
+        });
+    }
+    if candidates.len() > 1 {
+        context["languageId"] = json!({
+            "mode": "DetectContinuous",
+            "Priority": "PrioritizeLatency",
+            "languages": candidates,
+            "onSuccess": { "action": "Recognize" },
+            "onUnknown": { "action": "None" },
+        });
+        context["phraseOutput"] = json!({
+            "interimResults": { "resultType": "Auto" },
+            "phraseResults": { "resultType": "Always" },
+        });
+    }
+    context
+}
+
+/// The service's messages belong to a turn, which it ends now and again,
+/// e.g. after a long silence, for the client to start another. Offsets are
+/// counted from the start of each turn's audio, so the turn keeps where it
+/// started in the audio as a whole.
+#[derive(Default)]
+struct Turn {
+    current: Mutex<(String, Duration)>,
+}
+
+impl Turn {
+    /// Move on to a new turn starting `offset` into the audio, giving its
+    /// request ID
+    fn start(&self, offset: Duration) -> String {
+        let request_id = uuid::Uuid::new_v4().simple().to_string();
+        *self.current.lock().unwrap() = (request_id.clone(), offset);
+        request_id
+    }
+
+    fn current(&self) -> String {
+        self.current.lock().unwrap().0.clone()
+    }
+
+    /// Where the turn started in the audio, if `request_id` is the current
+    /// one
+    fn offset(&self, request_id: &str) -> Option<Duration> {
+        let current = self.current.lock().unwrap();
+        current
+            .0
+            .eq_ignore_ascii_case(request_id)
+            .then_some(current.1)
+    }
+}
+
+/// Move a line's offsets from the start of its turn to the start of the
+/// audio
+fn shift_offsets(line: &mut Line, by: Duration) {
+    let Some(utterance) = line.utterance_mut() else {
+        return;
+    };
+    let offsets = std::iter::once(&mut utterance.offset)
+        .chain(utterance.words.iter_mut().map(|word| &mut word.offset));
+    for offset in offsets.flatten() {
+        *offset += by;
+    }
+}
+
+/// Length of `bytes` of audio at `rate` bytes per second
+fn audio_duration(bytes: usize, rate: u32) -> Duration {
+    Duration::from_micros(bytes as u64 * 1_000_000 / u64::from(rate))
+}
+
+fn headers(path: &str, request_id: &str) -> Vec<(String, String)> {
+    vec![
+        ("Path".into(), path.into()),
+        ("X-RequestId".into(), request_id.into()),
+        ("X-Timestamp".into(), iso_timestamp(SystemTime::now())),
+    ]
+}
+
+fn json_message(
+    path: &str,
+    request_id: &str,
+    body: &serde_json::Value,
+) -> Message {
+    let mut headers = headers(path, request_id);
+    headers.push(("Content-Type".into(), "application/json".into()));
+    Message::text(make_text_payload(headers, Some(&body.to_string())))
+}
+
+/// A piece of audio, or the end of it if `None`
+fn audio_message(request_id: &str, data: Option<&[u8]>) -> Message {
+    Message::binary(make_binary_payload(headers("audio", request_id), data))
+}
+
+/// What each turn starts with: the recognition setup, then the audio's
+/// format along with its header if it has one. The context is sent again
+/// with each turn, as the Speech SDKs do, so that later turns keep the
+/// diarization, phrase list and language detection.
+struct TurnStart {
+    context: serde_json::Value,
+    encoding: AudioEncoding,
+    header: Vec<u8>,
+}
+
+impl TurnStart {
+    async fn send(
+        &self,
+        connection: &azure_speech::Client,
+        request_id: &str,
+    ) -> Result<()> {
+        let content_type = match self.encoding {
+            AudioEncoding::WebmOpus => "audio/webm; codecs=opus",
+            AudioEncoding::Pcm16kMono | AudioEncoding::Wav16kMono => {
+                "audio/wav"
+            }
+        };
+        let mut headers = headers("audio", request_id);
+        headers.push(("Content-Type".into(), content_type.into()));
+        let header = (!self.header.is_empty()).then_some(&self.header[..]);
+        for message in [
+            json_message("speech.context", request_id, &self.context),
+            Message::binary(make_binary_payload(headers, header)),
+        ] {
+            connection
+                .send(message)
+                .await
+                .map_err(|err| eyre!("{err:?}"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Split off the 44-byte header which WAV captures start with, as the
+/// service takes it at the start of each turn rather than with the audio
+async fn split_wav_header(
+    audio: &mut (impl Stream<Item = Vec<u8>> + Unpin),
+) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut header = Vec::new();
+    while header.len() < audio::wav_header().len() {
+        let chunk = audio
+            .next()
+            .await
+            .ok_or_else(|| eyre!("Audio ended before its WAV header"))?;
+        header.extend(chunk);
+    }
+    let rest = header.split_off(audio::wav_header().len());
+    Ok((header, rest))
+}
+
+/// Start recognising `audio` on a connected client.
+///
+/// This speaks the Speech websocket protocol over the client's connection
+/// rather than going through `recognizer::Client::recognize`. azure-speech
+/// 0.10 builds its `speech.context` from `recognizer::Config` alone, which
+/// has no setting for speaker diarization, and sends it only once per
+/// session: after `turn.end` it re-sends just the audio header. Its event
+/// offsets also start again from zero with each turn. It relies on
+/// azure-speech exposing the connection as `recognizer::Client::client`;
+/// this can go once the crate can ask for diarization itself.
+async fn recognise(
+    client: recognizer::Client,
+    mut audio: impl Stream<Item = Vec<u8>> + Send + Unpin + 'static,
+    encoding: AudioEncoding,
+    setup: Setup,
+) -> Result<AzureSession> {
+    let connection = client.client.clone();
+    let messages = connection.stream().await.map_err(|err| eyre!("{err:?}"))?;
+
+    let (header, buffer) = match encoding {
+        AudioEncoding::Wav16kMono => split_wav_header(&mut audio).await?,
+        AudioEncoding::WebmOpus | AudioEncoding::Pcm16kMono => {
+            (Vec::new(), Vec::new())
+        }
+    };
+    let start = TurnStart {
+        context: setup.context,
+        encoding,
+        header,
+    };
+    let turn = Arc::new(Turn::default());
+    let request_id = turn.start(Duration::ZERO);
+    connection
+        .send(json_message("speech.config", &request_id, &speech_config()))
+        .await
+        .map_err(|err| eyre!("{err:?}"))?;
+    start.send(&connection, &request_id).await?;
+
+    let (restart_tx, restarts) = mpsc::channel(1);
+    tokio::spawn(stream_audio(
+        connection,
+        audio,
+        buffer,
+        Arc::clone(&turn),
+        restarts,
+        start,
+    ));
+
+    let candidates = setup.candidates;
+    let lines = messages.stop_after(|message| message.is_err()).filter_map(
+        move |message| {
+            let message = match message {
+                Ok(message) => message,
+                Err(err) => {
+                    return Some(Err(eyre!("{err:?}")));
+                }
+            };
+            let offset = turn.offset(&message.id)?;
+            if message.path == "turn.end" {
+                let _ = restart_tx.try_send(());
+                return None;
+            }
+            let mut line =
+                handle_message(&message.path, &message.data, &candidates);
+            if let Some(Ok(line)) = &mut line {
+                shift_offsets(line, offset);
+            }
+            line
+        },
+    );
+
+    Ok(AzureSession {
+        client,
+        lines: Box::pin(lines),
+    })
+}
+
+/// Send the audio in pieces, starting a new turn whenever the service ends
+/// one
+async fn stream_audio(
+    connection: azure_speech::Client,
+    mut audio: impl Stream<Item = Vec<u8>> + Unpin,
+    mut buffer: Vec<u8>,
+    turn: Arc<Turn>,
+    mut restarts: mpsc::Receiver<()>,
+    start: TurnStart,
+) {
+    let mut request_id = turn.current();
+    // Where the current turn started in the audio, and how much of it has
+    // been sent since
+    let mut turn_offset = Duration::ZERO;
+    let mut turn_started = Instant::now();
+    let mut turn_bytes = 0;
+    loop {
+        tokio::select! {
+            Some(()) = restarts.recv() => {
+                turn_offset += match start.encoding.byte_rate() {
+                    Some(rate) => audio_duration(turn_bytes, rate),
+                    // Compressed audio is sent as fast as it is captured
+                    None => turn_started.elapsed(),
+                };
+                turn_started = Instant::now();
+                turn_bytes = 0;
+                request_id = turn.start(turn_offset);
+                if let Err(err) = start.send(&connection, &request_id).await {
+                    warn!("Unable to start a new turn: {err}");
+                    return;
+                }
+            }
+            chunk = audio.next() => {
+                let Some(chunk) = chunk else { break };
+                buffer.extend(chunk);
+                while buffer.len() >= AUDIO_CHUNK_SIZE {
+                    let data =
+                        buffer.drain(..AUDIO_CHUNK_SIZE).collect::<Vec<_>>();
+                    let message = audio_message(&request_id, Some(&data));
+                    if connection.send(message).await.is_err() {
+                        warn!("Failed to send audio");
+                        return;
+                    }
+                    turn_bytes += data.len();
+                }
+            }
+        }
+    }
+
+    // Send what's left, then mark the end of the audio
+    let rest = (!buffer.is_empty()).then_some(&buffer[..]);
+    for data in [rest, None] {
+        let _ = connection.send(audio_message(&request_id, data)).await;
+    }
+}
+
+pub(super) struct AzureSession {
+    client: recognizer::Client,
+    lines: Pin<Box<dyn Stream<Item = Result<Line>> + Send>>,
+}
+
+impl Stream for AzureSession {
+    type Item = Result<Line>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        self.lines.as_mut().poll_next(cx)
+    }
+}
+
+impl RecognitionBackend for AzureBackend {
+    type Session = AzureSession;
+
+    async fn connect(
+        &self,
+        setup_state: &SetupState,
+        config: &Config,
+    ) -> Result<AzureSession> {
+        let (client, setup) = self.open(setup_state, config).await?;
+
+        // Opus is only available when ffmpeg does the encoding
+        let encoding = match config.audio.capture {
+            Capture::Ffmpeg => AudioEncoding::WebmOpus,
+            Capture::Native => AudioEncoding::Wav16kMono,
+        };
+        let stream =
+            audio::capture(&config.audio, setup_state, encoding).await?;
+
+        recognise(client, stream, encoding, setup).await
+    }
+
+    async fn disconnect(&self, session: AzureSession) {
+        if let Err(err) = session.client.disconnect().await {
+            warn!("Disconnection failed: {err}");
+        }
+    }
+}
+
+fn language_from_locale(locale: &str) -> recognizer::Language {
+    match locale {
+        "en-GB" => recognizer::Language::EnGb,
+        "en-IE" => recognizer::Language::EnIe,
+        "en-US" => recognizer::Language::EnUs,
+        "ja-JP" => recognizer::Language::JaJp,
+        // Anything else is passed through as-is, so that any locale the
+        // service supports can be configured
+        other => recognizer::Language::Custom(other.to_string()),
+    }
+}
+
+/// Azure reports offsets and durations in 100 ns ticks
+fn ticks(ticks: u64) -> Duration {
+    Duration::from_nanos(ticks * 100)
+}
+
+/// A phrase or a hypothesis of one, in either output format
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Recognition {
+    /// Only given for phrases
+    recognition_status: Option<String>,
+    /// Text of a hypothesis
+    text: Option<String>,
+    /// Text of a phrase
+    display_text: Option<String>,
+    #[serde(default)]
+    offset: u64,
+    #[serde(default)]
+    duration: u64,
+    primary_language: Option<PrimaryLanguage>,
+    /// Given when diarization is on, as `Unknown` until the speaker has been
+    /// identified
+    speaker_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct PrimaryLanguage {
+    language: String,
+}
+
+fn utterance(
+    recognition: Recognition,
+    text: String,
+    candidates: &[Arc<str>],
+) -> Utterance {
+    let language = recognition.primary_language.and_then(|detected| {
+        candidates
+            .iter()
+            .find(|locale| locale.eq_ignore_ascii_case(&detected.language))
+            .cloned()
+    });
+    Utterance {
+        text,
+        offset: Some(ticks(recognition.offset)),
+        duration: Some(ticks(recognition.duration)),
+        language,
+        speaker: recognition
+            .speaker_id
+            .filter(|speaker| speaker != "Unknown")
+            .map(Into::into),
+        ..Default::default()
+    }
+}
+
+/// The detailed form of a recognised phrase
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DetailedPhrase {
+    #[serde(default, rename = "NBest")]
+    n_best: Vec<NBest>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct NBest {
+    confidence: f32,
+    display: String,
+    #[serde(default)]
+    words: Vec<DetailedWord>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DetailedWord {
+    word: String,
+    offset: u64,
+    duration: u64,
+    confidence: Option<f32>,
+}
+
+/// Fill in confidence, words and alternatives from the raw message
+fn add_detail(utterance: &mut Utterance, raw: &str) {
+    let phrase = match serde_json::from_str::<DetailedPhrase>(raw) {
+        Ok(phrase) => phrase,
+        Err(err) => {
+            debug!("No detailed result: {err}");
+            return;
+        }
+    };
+    let mut n_best = phrase.n_best.into_iter();
+    let Some(best) = n_best.next() else {
+        return;
+    };
+
+    utterance.confidence = Some(best.confidence);
+    utterance.words = best
+        .words
+        .into_iter()
+        .map(|word| Word {
+            text: word.word,
+            offset: Some(ticks(word.offset)),
+            duration: Some(ticks(word.duration)),
+            confidence: word.confidence,
+        })
+        .collect();
+    utterance.alternatives = n_best
+        .map(|alternative| Alternative {
+            text: alternative.display,
+            confidence: Some(alternative.confidence),
+        })
+        .collect();
+}
+
+/// What tokio-websockets reports when the service answers the websocket
+/// handshake with something other than a protocol switch, followed by the
+/// HTTP status
+const HANDSHAKE_STATUS: &str =
+    "expected HTTP 101 Switching Protocols, got status code ";
+
+/// Tell rejected credentials apart from network trouble, which is worth
+/// retrying. The service refuses a bad key or region in the websocket
+/// handshake, and azure-speech only passes on its status as text. Errors
+/// once connected are never taken as rejection.
+fn handshake_error(err: azure_speech::Error) -> Report {
+    let status = match &err {
+        azure_speech::Error::Forbidden => Some(403),
+        azure_speech::Error::ConnectionError(message) => message
+            .strip_prefix(HANDSHAKE_STATUS)
+            .and_then(|status| status.parse::<u16>().ok()),
+        _ => None,
+    };
+    let description = format!("{err:?}");
+    if matches!(status, Some(401 | 403)) {
+        AuthError(description).into()
+    } else {
+        eyre!(description)
+    }
+}
+
+/// Turn a message from the service into a line, if it gives one
+fn handle_message(
+    path: &str,
+    data: &Data,
+    candidates: &[Arc<str>],
+) -> Option<Result<Line>> {
+    let Data::Text(Some(raw)) = data else {
+        return None;
+    };
+    let parse = || {
+        serde_json::from_str::<Recognition>(raw)
+            .map_err(|err| eyre!("Unable to parse {path}: {err}"))
+    };
+    match path {
+        "speech.hypothesis" | "speech.fragment" => {
+            Some(parse().map(|result| {
+                let text = result.text.clone().unwrap_or_default();
+                Line::Recognising(utterance(result, text, candidates))
+            }))
+        }
+        "speech.phrase" => {
+            let result = match parse() {
+                Ok(result) => result,
+                Err(err) => return Some(Err(err)),
+            };
+            // Silence, or the end of the audio
+            if result.recognition_status.as_deref() != Some("Success") {
+                debug!("No phrase: {:?}", result.recognition_status);
+                return None;
+            }
+            let text = result.display_text.clone().unwrap_or_default();
+            let mut utterance = utterance(result, text, candidates);
+            add_detail(&mut utterance, raw);
+            Some(Ok(Line::Recognised(utterance)))
+        }
+        path => {
+            debug!("Unhandled message: {path}");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        super::{
+            Connection, ControlMessage, RunState, Status,
+            mock::MockTranslator,
+            speech_service::{Script, SpeechService},
+            start_inner,
+        },
+        *,
+    };
+    use pretty_assertions::assert_eq;
+    use tokio::{
+        sync::{mpsc, watch},
+        time::timeout,
+    };
+
+    const DETAILED: &str = r#"{
+        "RecognitionStatus": "Success",
+        "Offset": 5000000,
+        "Duration": 12000000,
+        "DisplayText": "Hello world.",
+        "NBest": [
+            {
+                "Confidence": 0.92,
+                "Lexical": "hello world",
+                "ITN": "hello world",
+                "MaskedITN": "hello world",
+                "Display": "Hello world.",
+                "Words": [
+                    {
+                        "Word": "hello",
+                        "Offset": 5000000,
+                        "Duration": 4000000,
+                        "Confidence": 0.97
+                    },
+                    {
+                        "Word": "world",
+                        "Offset": 9000000,
+                        "Duration": 8000000,
+                        "Confidence": 0.61
+                    }
+                ]
+            },
+            {
+                "Confidence": 0.4,
+                "Lexical": "hello whirled",
+                "Display": "Hello whirled."
+            }
+        ]
+    }"#;
+
+    #[test]
+    fn test_add_detail() {
+        let mut utterance = Utterance::from("Hello world.");
+        add_detail(&mut utterance, DETAILED);
+
+        assert_eq!(utterance.confidence, Some(0.92));
+        assert_eq!(
+            utterance.words,
+            [
+                Word {
+                    text: "hello".into(),
+                    offset: Some(Duration::from_millis(500)),
+                    duration: Some(Duration::from_millis(400)),
+                    confidence: Some(0.97),
+                },
+                Word {
+                    text: "world".into(),
+                    offset: Some(Duration::from_millis(900)),
+                    duration: Some(Duration::from_millis(800)),
+                    confidence: Some(0.61),
+                },
+            ]
+        );
+        assert_eq!(
+            utterance.alternatives,
+            [Alternative {
+                text: "Hello whirled.".into(),
+                confidence: Some(0.4),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_simple_format() {
+        let mut utterance = Utterance::from("Hello world.");
+        add_detail(
+            &mut utterance,
+            r#"{"RecognitionStatus":"Success","DisplayText":"Hello world."}"#,
+        );
+        assert_eq!(utterance, Utterance::from("Hello world."));
+    }
+
+    #[test]
+    fn test_handshake_error() {
+        let err = handshake_error(azure_speech::Error::ConnectionError(
+            format!("{HANDSHAKE_STATUS}401"),
+        ));
+        assert!(err.downcast_ref::<AuthError>().is_some());
+        let err = handshake_error(azure_speech::Error::Forbidden);
+        assert!(err.downcast_ref::<AuthError>().is_some());
+
+        // Only the status counts, not numbers elsewhere in the description
+        for err in [
+            azure_speech::Error::ConnectionError(format!(
+                "{HANDSHAKE_STATUS}503"
+            )),
+            azure_speech::Error::IOError(
+                "Connection reset by peer on 127.0.0.1:40137".into(),
+            ),
+        ] {
+            let err = handshake_error(err);
+            assert!(err.downcast_ref::<AuthError>().is_none());
+        }
+    }
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Recognises silence instead of capturing from a device
+    struct Silent(AzureBackend);
+
+    impl RecognitionBackend for Silent {
+        type Session = AzureSession;
+
+        async fn connect(
+            &self,
+            setup_state: &SetupState,
+            config: &Config,
+        ) -> Result<AzureSession> {
+            let (client, setup) = self.0.open(setup_state, config).await?;
+            let silence = tokio_stream::iter([
+                audio::wav_header(),
+                vec![0; AUDIO_CHUNK_SIZE * 2],
+            ])
+            .chain(tokio_stream::pending());
+            recognise(client, silence, AudioEncoding::Wav16kMono, setup).await
+        }
+
+        async fn disconnect(&self, session: AzureSession) {
+            self.0.disconnect(session).await;
+        }
+    }
+
+    fn config(service: &SpeechService) -> Config {
+        Config {
+            key: Some("secret".into()),
+            speech_endpoint: Some(service.url.clone()),
+            languages: vec!["en-GB".into()],
+            ..Default::default()
+        }
+    }
+
+    fn phrase(text: &str) -> (&'static str, String) {
+        (
+            "speech.phrase",
+            format!(
+                r#"{{"RecognitionStatus":"Success","DisplayText":"{text}","Offset":0,"Duration":10000000}}"#
+            ),
+        )
+    }
+
+    /// Run the listener against the service until it gives a line or the
+    /// connection fails
+    fn start_listener(
+        service: &SpeechService,
+    ) -> (
+        mpsc::Receiver<Line>,
+        watch::Receiver<Status>,
+        tokio::task::JoinHandle<Result<()>>,
+    ) {
+        let (tx, rx) = mpsc::channel(10);
+        let (control_tx, control_rx) = mpsc::channel(5);
+        let (status_tx, status) = watch::channel(Status::default());
+        let config = config(service);
+        let backend = Silent(AzureBackend::new(&config).unwrap());
+        control_tx
+            .try_send(ControlMessage::SetState(RunState::Running))
+            .unwrap();
+        let task = tokio::spawn(async move {
+            // Keep the control channel open for as long as the listener runs
+            let _control_tx = control_tx;
+            start_inner(
+                tx,
+                control_rx,
+                watch::channel(None).0,
+                status_tx,
+                backend,
+                None::<MockTranslator>,
+                config,
+            )
+            .await
+        });
+        (rx, status, task)
+    }
+
+    async fn recv_text(rx: &mut mpsc::Receiver<Line>) -> String {
+        match timeout(TIMEOUT, rx.recv()).await.unwrap().unwrap() {
+            Line::Recognised(utterance) => utterance.text,
+            line => panic!("Unexpected line {line:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_recognition() {
+        let service = SpeechService::start([Script::Accept(vec![
+            (
+                "speech.hypothesis",
+                r#"{"Text":"hello","Offset":5000000,"Duration":4000000}"#
+                    .into(),
+            ),
+            ("speech.phrase", DETAILED.into()),
+        ])])
+        .await;
+        let config = config(&service);
+        let mut setup_state = SetupState::new(
+            &config,
+            watch::channel(None).0,
+            watch::channel(Status::default()).0,
+        );
+        setup_state.profanity = ProfanityMode::Masked;
+        let backend = Silent(AzureBackend::new(&config).unwrap());
+
+        let mut session = backend.connect(&setup_state, &config).await.unwrap();
+        let line = timeout(TIMEOUT, session.next()).await.unwrap().unwrap();
+        assert_eq!(
+            line.unwrap(),
+            Line::Recognising(Utterance {
+                offset: Some(Duration::from_millis(500)),
+                duration: Some(Duration::from_millis(400)),
+                ..Utterance::from("hello")
+            })
+        );
+        let line = timeout(TIMEOUT, session.next()).await.unwrap().unwrap();
+        let Line::Recognised(utterance) = line.unwrap() else {
+            panic!("Expected a recognised line");
+        };
+        assert_eq!(utterance.text, "Hello world.");
+        assert_eq!(utterance.confidence, Some(0.92));
+        assert_eq!(utterance.alternatives.len(), 1);
+        backend.disconnect(session).await;
+
+        let requests = service.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(
+            requests[0].uri,
+            "/speech/recognition/conversation/cognitiveservices/v1\
+             ?language=en-GB&format=detailed&profanity=masked\
+             &wordLevelTimestamps=true"
+        );
+        assert_eq!(requests[0].key.as_deref(), Some("secret"));
+    }
+
+    #[tokio::test]
+    async fn test_speakers() {
+        let service = SpeechService::start([Script::Accept(vec![
+            (
+                "speech.hypothesis",
+                r#"{"Text":"hello","Offset":0,"Duration":4000000,"SpeakerId":"Unknown"}"#
+                    .into(),
+            ),
+            (
+                "speech.phrase",
+                r#"{"RecognitionStatus":"Success","DisplayText":"Hello.","Offset":0,"Duration":5000000,"SpeakerId":"Guest-1"}"#
+                    .into(),
+            ),
+        ])])
+        .await;
+        let config = config(&service);
+        let setup_state = SetupState::new(
+            &config,
+            watch::channel(None).0,
+            watch::channel(Status::default()).0,
+        );
+        let backend = Silent(AzureBackend::new(&config).unwrap());
+
+        // Speakers are only given once the service has told them apart
+        let mut session = backend.connect(&setup_state, &config).await.unwrap();
+        let line = timeout(TIMEOUT, session.next()).await.unwrap().unwrap();
+        let Line::Recognising(utterance) = line.unwrap() else {
+            panic!("Expected a partial line");
+        };
+        assert_eq!(utterance.speaker, None);
+        let line = timeout(TIMEOUT, session.next()).await.unwrap().unwrap();
+        assert_eq!(
+            line.unwrap(),
+            Line::Recognised(Utterance {
+                offset: Some(Duration::ZERO),
+                duration: Some(Duration::from_millis(500)),
+                speaker: Some("Guest-1".into()),
+                ..Utterance::from("Hello.")
+            })
+        );
+        backend.disconnect(session).await;
+
+        let requests = service.requests();
+        assert_eq!(
+            requests[0].context["phraseDetection"]["speakerDiarization"]["mode"],
+            "Anonymous"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_turn_offsets() {
+        // Each turn counts its offsets from its own start
+        let service = SpeechService::start([Script::Turns(vec![
+            vec![phrase("One.")],
+            vec![(
+                "speech.phrase",
+                r#"{"RecognitionStatus":"Success","DisplayText":"Two.","Offset":0,"Duration":4000000,"NBest":[{"Confidence":0.9,"Display":"Two.","Words":[{"Word":"two","Offset":1000000,"Duration":3000000}]}]}"#
+                    .into(),
+            )],
+        ])])
+        .await;
+        let config = config(&service);
+        let setup_state = SetupState::new(
+            &config,
+            watch::channel(None).0,
+            watch::channel(Status::default()).0,
+        );
+        let backend = Silent(AzureBackend::new(&config).unwrap());
+
+        let mut session = backend.connect(&setup_state, &config).await.unwrap();
+        let line = timeout(TIMEOUT, session.next()).await.unwrap().unwrap();
+        let Line::Recognised(first) = line.unwrap() else {
+            panic!("Expected a recognised line");
+        };
+        assert_eq!(first.offset, Some(Duration::ZERO));
+        let line = timeout(TIMEOUT, session.next()).await.unwrap().unwrap();
+        let Line::Recognised(second) = line.unwrap() else {
+            panic!("Expected a recognised line");
+        };
+        backend.disconnect(session).await;
+
+        // The second turn starts after the audio sent in the first
+        let requests = service.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].audio > 0);
+        let turn_start = audio_duration(
+            requests[1].audio,
+            AudioEncoding::Wav16kMono.byte_rate().unwrap(),
+        );
+        assert_eq!(second.offset, Some(turn_start));
+        assert_eq!(
+            second.words[0].offset,
+            Some(turn_start + Duration::from_millis(100))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reconnects() {
+        let service = SpeechService::start([
+            Script::Reject("503 Service Unavailable"),
+            Script::AcceptThenClose(vec![phrase("One.")]),
+            Script::Accept(vec![phrase("Two.")]),
+        ])
+        .await;
+        let (mut rx, status, task) = start_listener(&service);
+
+        assert_eq!(recv_text(&mut rx).await, "One.");
+        assert_eq!(recv_text(&mut rx).await, "Two.");
+        let status = status.borrow().clone();
+        assert_eq!(status.run_state, RunState::Running);
+        assert_eq!(status.connection, Connection::Listening);
+        assert_eq!(service.requests().len(), 2);
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_retries_after_disconnect() {
+        // Numbers which look like a status in an error once connected
+        // mustn't be taken as the key being rejected
+        let service = SpeechService::start([
+            Script::Close("Request 401 Unauthorized idle for too long"),
+            Script::Accept(vec![phrase("One.")]),
+        ])
+        .await;
+        let (mut rx, status, task) = start_listener(&service);
+
+        assert_eq!(recv_text(&mut rx).await, "One.");
+        let status = status.borrow().clone();
+        assert_eq!(status.run_state, RunState::Running);
+        assert_eq!(status.connection, Connection::Listening);
+        assert_eq!(service.requests().len(), 2);
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_rejected_key() {
+        let service =
+            SpeechService::start([Script::Reject("401 Unauthorized")]).await;
+        let (_rx, mut status, task) = start_listener(&service);
+
+        let status = timeout(
+            TIMEOUT,
+            status.wait_for(|status| status.connection == Connection::Failed),
+        )
+        .await
+        .unwrap()
+        .unwrap()
+        .clone();
+        assert_eq!(status.run_state, RunState::Stopped);
+        assert!(
+            status
+                .last_error
+                .unwrap()
+                .starts_with("Authentication failed:")
+        );
+        task.abort();
+    }
+}
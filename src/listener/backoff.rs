@@ -0,0 +1,81 @@
+//! Delays between attempts at reconnecting to the recogniser, growing while
+//! the service stays unreachable
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+const INITIAL_DELAY: Duration = Duration::from_millis(500);
+const MAX_DELAY: Duration = Duration::from_secs(30);
+/// Consecutive failures before giving up on the session
+pub const MAX_ATTEMPTS: u32 = 10;
+
+#[derive(Debug, Default)]
+pub struct Backoff {
+    attempt: u32,
+}
+
+impl Backoff {
+    /// Count a failure, giving the number of the next attempt and how long
+    /// to wait before making it, or `None` once the attempts are used up
+    pub fn next(&mut self) -> Option<(u32, Duration)> {
+        if self.attempt >= MAX_ATTEMPTS {
+            return None;
+        }
+        self.attempt += 1;
+        Some((self.attempt, delay(self.attempt, jitter())))
+    }
+
+    /// Start counting again after the recogniser has been working
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+/// Delay before an attempt, counting from 1. It doubles each time up to a
+/// cap, and `jitter` between 0 and 1 picks a point in its upper half so that
+/// several captioners which lost the network together don't retry in step.
+fn delay(attempt: u32, jitter: f64) -> Duration {
+    let full = INITIAL_DELAY
+        .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+        .min(MAX_DELAY);
+    full.mul_f64(0.5 + jitter.clamp(0.0, 1.0) / 2.0)
+}
+
+/// A different number between 0 and 1 on each call. The standard library's
+/// randomly keyed hasher is plenty for spreading out retries.
+fn jitter() -> f64 {
+    let bits = RandomState::new().build_hasher().finish() >> 11;
+    bits as f64 / (1_u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_delay() {
+        assert_eq!(delay(1, 0.0), Duration::from_millis(250));
+        assert_eq!(delay(1, 1.0), Duration::from_millis(500));
+        assert_eq!(delay(3, 1.0), Duration::from_secs(2));
+        assert_eq!(delay(20, 0.0), Duration::from_secs(15));
+        assert_eq!(delay(20, 1.0), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_gives_up() {
+        let mut backoff = Backoff::default();
+        for attempt in 1..=MAX_ATTEMPTS {
+            let (next, delay) = backoff.next().unwrap();
+            assert_eq!(next, attempt);
+            assert!(delay <= MAX_DELAY);
+        }
+        assert_eq!(backoff.next(), None);
+
+        backoff.reset();
+        assert_eq!(backoff.next().map(|(attempt, _)| attempt), Some(1));
+    }
+}
@@ -0,0 +1,124 @@
+//! Scripted stand-ins for the speech and translation services, used to
+//! exercise the listener state machine without live Azure endpoints
+
+use super::{AuthError, RecognitionBackend, SetupState, Translator};
+use crate::{Line, Result, Utterance, config::Config};
+use color_eyre::eyre::eyre;
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+use tokio_stream::{Stream, StreamExt};
+
+#[derive(Clone, Debug)]
+pub enum MockEvent {
+    Recognising(&'static str),
+    Recognised(&'static str),
+    /// A final line in the given language, reported when auto-detecting
+    RecognisedIn(&'static str, &'static str),
+    /// The service closes the connection
+    Drop,
+}
+
+/// What happens on a single call to `connect`
+#[derive(Clone, Debug)]
+pub enum MockConnection {
+    /// Accept the connection and replay the events, then stay connected
+    Accept(Vec<MockEvent>),
+    /// Refuse the connection with an authentication error
+    AuthError,
+    /// Fail to connect as if the service could not be reached
+    Unreachable,
+}
+
+#[derive(Clone, Default)]
+pub(super) struct MockBackend {
+    connections: Arc<Mutex<VecDeque<MockConnection>>>,
+    connects: Arc<AtomicUsize>,
+    /// Whether each connection asked for language auto-detection
+    auto_detect: Arc<Mutex<Vec<bool>>>,
+    disconnects: Arc<AtomicUsize>,
+}
+
+impl MockBackend {
+    pub fn new(connections: impl IntoIterator<Item = MockConnection>) -> Self {
+        Self {
+            connections: Arc::new(Mutex::new(
+                connections.into_iter().collect(),
+            )),
+            ..Default::default()
+        }
+    }
+
+    pub fn connects(&self) -> usize {
+        self.connects.load(Ordering::SeqCst)
+    }
+
+    pub fn disconnects(&self) -> usize {
+        self.disconnects.load(Ordering::SeqCst)
+    }
+
+    pub fn auto_detect(&self) -> Vec<bool> {
+        self.auto_detect.lock().unwrap().clone()
+    }
+}
+
+impl RecognitionBackend for MockBackend {
+    type Session = Pin<Box<dyn Stream<Item = Result<Line>> + Send>>;
+
+    async fn connect(
+        &self,
+        setup_state: &SetupState,
+        _config: &Config,
+    ) -> Result<Self::Session> {
+        self.connects.fetch_add(1, Ordering::SeqCst);
+        self.auto_detect
+            .lock()
+            .unwrap()
+            .push(setup_state.auto_detect);
+        let connection = self.connections.lock().unwrap().pop_front();
+        let events = match connection {
+            Some(MockConnection::Accept(events)) => events,
+            Some(MockConnection::AuthError) => {
+                return Err(AuthError("401 Unauthorized".into()).into());
+            }
+            Some(MockConnection::Unreachable) => {
+                return Err(eyre!("Connection refused"));
+            }
+            None => return Err(eyre!("No more scripted connections")),
+        };
+
+        let lines = events.into_iter().map(|event| match event {
+            MockEvent::Recognising(text) => Ok(Line::Recognising(text.into())),
+            MockEvent::Recognised(text) => Ok(Line::Recognised(text.into())),
+            MockEvent::RecognisedIn(language, text) => {
+                Ok(Line::Recognised(Utterance {
+                    language: Some(language.into()),
+                    ..Utterance::from(text)
+                }))
+            }
+            MockEvent::Drop => Err(eyre!("Connection dropped")),
+        });
+
+        Ok(Box::pin(
+            tokio_stream::iter(lines).chain(tokio_stream::pending()),
+        ))
+    }
+
+    async fn disconnect(&self, _session: Self::Session) {
+        self.disconnects.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// Translates by tagging the text with the target language, e.g. `[ja] hi`
+pub struct MockTranslator;
+
+impl Translator for MockTranslator {
+    async fn translate(&self, text: &str, to: &str) -> Result<String> {
+        Ok(format!("[{to}] {text}"))
+    }
+}
@@ -0,0 +1,1346 @@
+use crate::{
+    ControlMessage, Line, ProfanityMode, Replay, Result, RunState, Wordlist,
+    config::{Backend, Config},
+    transcript::{Transcript, file_timestamp},
+};
+use backoff::Backoff;
+use color_eyre::{Report, eyre::eyre};
+use profanity::Blocklist;
+use recording::Recording;
+use replacements::Replacements;
+use std::{
+    fmt,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
+use test_data::TestData;
+use tokio::{
+    sync::{mpsc, watch},
+    time::Instant,
+};
+use tokio_stream::{Stream, StreamExt};
+use translation::{AzureTranslator, Translator};
+
+mod audio;
+mod azure;
+mod backoff;
+#[cfg(test)]
+mod mock;
+#[cfg(feature = "native-audio")]
+mod native_audio;
+mod profanity;
+mod recording;
+mod replacements;
+#[cfg(test)]
+mod speech_service;
+mod test_data;
+mod translation;
+#[cfg(feature = "vosk")]
+mod vosk;
+
+pub use audio::{AudioLevel, list_devices as list_audio_devices};
+
+/// Length of session after which a failure no longer counts towards giving
+/// up
+const HEALTHY_SESSION: Duration = Duration::from_secs(60);
+
+/// A speech recognition engine that turns audio into a stream of caption
+/// lines
+pub trait RecognitionBackend: Send + Sync + 'static {
+    type Session: Stream<Item = Result<Line>> + Send + Unpin;
+
+    /// Start a new recognition session using the current setup. An error
+    /// from here or from the returned stream causes the session to be
+    /// reconnected, unless it is an [`AuthError`].
+    fn connect(
+        &self,
+        setup_state: &SetupState,
+        config: &Config,
+    ) -> impl Future<Output = Result<Self::Session>> + Send;
+
+    /// Tear down a session which is no longer needed
+    fn disconnect(
+        &self,
+        session: Self::Session,
+    ) -> impl Future<Output = ()> + Send;
+}
+
+/// The service rejected the credentials, which no amount of reconnecting
+/// will fix. Backends return this so that the session fails straight away
+/// rather than retrying.
+#[derive(Debug)]
+pub struct AuthError(pub String);
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Authentication failed: {}", self.0)
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// What the listener is doing, for the controls
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Status {
+    /// The state the listener is in, which falls back to `Stopped` by itself
+    /// when a session fails or a replay finishes
+    pub run_state: RunState,
+    pub connection: Connection,
+    /// Most recent error from the recogniser, kept until the next Run, or
+    /// why the test data couldn't be played
+    pub last_error: Option<String>,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Connection {
+    #[default]
+    Idle,
+    Connecting,
+    Listening,
+    /// Waiting to make the given attempt at reconnecting
+    Reconnecting {
+        attempt: u32,
+        retry_at: std::time::Instant,
+    },
+    /// The last Run session gave up, on an authentication error or after too
+    /// many attempts
+    Failed,
+}
+
+pub struct SetupState {
+    pub language: Arc<str>,
+    /// Identify the language continuously from
+    /// [`Config::detect_languages`] rather than using `language`
+    pub auto_detect: bool,
+    pub wordlist: Option<Arc<str>>,
+    pub profanity: ProfanityMode,
+    /// Filter applied locally to match `profanity`, whichever backend is in
+    /// use
+    pub blocklist: Blocklist,
+    /// Capture device chosen in the controls, overriding the config
+    pub audio_device: Option<Arc<str>>,
+    /// Recording to recognise instead of the input, with the source resolved
+    /// to a path or URL
+    pub replay: Option<Replay>,
+    /// Language to translate finished lines into
+    pub translation: Option<Arc<str>>,
+    /// Corrections for the current wordlist, applied to every line
+    pub replacements: Replacements,
+    /// File replayed by the Test mode, `None` for the built-in lines
+    pub test_data: Option<Arc<str>>,
+    pub test_loop: bool,
+    /// Where the capture reports the input level, for the meter in the
+    /// controls
+    pub levels: watch::Sender<Option<AudioLevel>>,
+    /// Where the listener reports its state and connection
+    pub status: watch::Sender<Status>,
+    /// Transcript of the last run, kept so that lines still being moderated
+    /// when it ended can be corrected in the saved copy
+    pub last_transcript: Option<Transcript>,
+    /// Name shared by the current Run session's audio recording and session
+    /// log, e.g. `session-20261017-093000`
+    pub session_name: Option<String>,
+}
+
+impl SetupState {
+    fn new(
+        config: &Config,
+        levels: watch::Sender<Option<AudioLevel>>,
+        status: watch::Sender<Status>,
+    ) -> Self {
+        Self {
+            language: config
+                .languages
+                .first()
+                .cloned()
+                .unwrap_or_else(|| crate::LANGUAGE_OPTIONS[0].into()),
+            auto_detect: false,
+            wordlist: None,
+            profanity: ProfanityMode::default(),
+            blocklist: config
+                .profanity_blocklist
+                .as_deref()
+                .map(|path| {
+                    Blocklist::load(path)
+                        .inspect_err(|err| error!("{err:?}"))
+                        .unwrap_or_default()
+                })
+                .unwrap_or_default(),
+            audio_device: None,
+            replay: None,
+            translation: None,
+            replacements: Replacements::default(),
+            test_data: None,
+            test_loop: true,
+            levels,
+            status,
+            last_transcript: None,
+            session_name: None,
+        }
+    }
+}
+
+// spx recognize --microphone --phrases @/tmp/words.txt --language en-GB
+
+pub fn start(
+    tx: mpsc::Sender<Line>,
+    control_rx: mpsc::Receiver<ControlMessage>,
+    levels: watch::Sender<Option<AudioLevel>>,
+    status: watch::Sender<Status>,
+    config: Config,
+) -> Result<()> {
+    let translator = config.translator.clone().map(AzureTranslator::new);
+    match config.backend {
+        Backend::Azure => {
+            let backend = azure::AzureBackend::new(&config)?;
+            spawn(tx, control_rx, levels, status, backend, translator, config);
+        }
+        #[cfg(feature = "vosk")]
+        Backend::Vosk => {
+            let backend = vosk::VoskBackend::new(&config)?;
+            spawn(tx, control_rx, levels, status, backend, translator, config);
+        }
+        #[cfg(not(feature = "vosk"))]
+        Backend::Vosk => {
+            return Err(eyre!(
+                "The vosk backend requires building with `--features vosk`"
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn spawn<B: RecognitionBackend, T: Translator>(
+    tx: mpsc::Sender<Line>,
+    control_rx: mpsc::Receiver<ControlMessage>,
+    levels: watch::Sender<Option<AudioLevel>>,
+    status: watch::Sender<Status>,
+    backend: B,
+    translator: Option<T>,
+    config: Config,
+) {
+    tokio::task::spawn(async move {
+        start_inner(tx, control_rx, levels, status, backend, translator, config)
+            .await
+            .unwrap()
+    });
+}
+
+// State machine:
+// - Stopped: wait for control channel message to transition to other state
+// - Running: start the recognition backend and then select! on that and the
+//   control channel
+// - Test: start test loop and then select! on that and the control channel
+async fn start_inner<B: RecognitionBackend, T: Translator>(
+    tx: mpsc::Sender<Line>,
+    mut control_rx: mpsc::Receiver<ControlMessage>,
+    levels: watch::Sender<Option<AudioLevel>>,
+    status: watch::Sender<Status>,
+    backend: B,
+    translator: Option<T>,
+    config: Config,
+) -> Result<()> {
+    let mut run_state = RunState::Stopped;
+    let mut setup_state = SetupState::new(&config, levels, status);
+    let translator = translator.map(Arc::new);
+
+    loop {
+        setup_state.status.send_modify(|status| {
+            status.run_state = run_state;
+            // A failure stays on show until the next Run
+            if run_state != RunState::Running
+                && status.connection != Connection::Failed
+            {
+                status.connection = Connection::Idle;
+            }
+        });
+        run_state = match run_state {
+            RunState::Stopped | RunState::HoldingSlide => {
+                wait_for_transition(&mut control_rx, &mut setup_state, &config)
+                    .await
+            }
+            RunState::Running => {
+                let mut transcript = Transcript::start();
+                let new_state = do_run(
+                    &tx,
+                    &mut control_rx,
+                    &mut setup_state,
+                    &backend,
+                    translator.as_ref(),
+                    &config,
+                    &mut transcript,
+                )
+                .await;
+
+                save_transcript(&transcript, &config);
+                setup_state.last_transcript = Some(transcript);
+
+                new_state
+            }
+            RunState::Test => {
+                run_test(
+                    &tx,
+                    &mut control_rx,
+                    &mut setup_state,
+                    translator.as_ref(),
+                    &config,
+                )
+                .await
+            }
+        };
+    }
+}
+
+async fn wait_for_transition(
+    control_rx: &mut mpsc::Receiver<ControlMessage>,
+    setup_state: &mut SetupState,
+    config: &Config,
+) -> RunState {
+    while let Some(msg) = control_rx.recv().await {
+        match msg {
+            ControlMessage::SetState(new_state) => return new_state,
+            other => handle_lang_and_wordlist(other, setup_state, config),
+        }
+    }
+    RunState::Stopped
+}
+
+async fn do_run<B: RecognitionBackend, T: Translator>(
+    tx: &mpsc::Sender<Line>,
+    control_rx: &mut mpsc::Receiver<ControlMessage>,
+    setup_state: &mut SetupState,
+    backend: &B,
+    translator: Option<&Arc<T>>,
+    config: &Config,
+    transcript: &mut Transcript,
+) -> RunState {
+    let session_name = format!("session-{}", file_timestamp(SystemTime::now()));
+    // The log goes next to the audio when that is being recorded
+    let audio_dir = config
+        .audio
+        .recordings_dir
+        .as_deref()
+        .filter(|_| config.audio.record && setup_state.replay.is_none());
+    let mut recording = audio_dir
+        .or(config.test_data_dir.as_deref())
+        .filter(|_| config.record_sessions)
+        .and_then(|dir| {
+            Recording::start(dir, &session_name)
+                .inspect_err(|err| error!("{err:?}"))
+                .ok()
+        });
+    setup_state.session_name = Some(session_name);
+
+    let mut backoff = Backoff::default();
+    setup_state.status.send_modify(|status| {
+        status.connection = Connection::Connecting;
+        status.last_error = None;
+    });
+
+    loop {
+        let mut session = match backend.connect(setup_state, config).await {
+            Ok(session) => session,
+            Err(err) => {
+                error!("Unable to connect: {err:?}");
+                if let Some(new_state) = back_off(
+                    err,
+                    &mut backoff,
+                    control_rx,
+                    setup_state,
+                    config,
+                    transcript,
+                )
+                .await
+                {
+                    return new_state;
+                }
+                continue;
+            }
+        };
+        transcript.connected();
+        setup_state
+            .status
+            .send_modify(|status| status.connection = Connection::Listening);
+        let connected_at = Instant::now();
+
+        tracing::info!("... Starting to listen from microphone ...");
+
+        let outcome = loop {
+            tokio::select! {
+                line = session.next() => {
+                    match line {
+                        Some(Ok(mut line)) => {
+                            backoff.reset();
+                            // Logged as the recogniser gave it, before the
+                            // replacements, profanity filter and numbering
+                            if let Some(recording) = &mut recording {
+                                recording.record(&line);
+                            }
+                            post_process(&mut line, setup_state);
+                            transcript.record(&line);
+                            if tx.try_send(line.clone()).is_err() {
+                                warn!("Line channel full");
+                            }
+                            translate_later(line, setup_state, translator, tx);
+                        }
+                        Some(Err(err)) => break Err(err),
+                        None if setup_state.replay.is_some() => {
+                            info!("Replay finished");
+                            break Ok(RunState::Stopped);
+                        }
+                        None => break Err(eyre!("Recognition stream ended")),
+                    }
+                }
+                msg = control_rx.recv() => {
+                    let Some(msg) = msg else { break Ok(RunState::Stopped) };
+                    match msg {
+                        ControlMessage::SetState(RunState::Running) => {}
+                        ControlMessage::SetState(new_state) => {
+                           break Ok(new_state);
+                        }
+                        ControlMessage::CorrectLine { id, text } => {
+                            correct(transcript, id, text, setup_state, config);
+                        }
+                        other => {
+                            let language = setup_state.language.clone();
+                            let auto_detect = setup_state.auto_detect;
+                            let profanity = setup_state.profanity;
+                            let audio_device = setup_state.audio_device.clone();
+                            let replay = setup_state.replay.clone();
+                            handle_lang_and_wordlist(
+                                other, setup_state, config,
+                            );
+                            if setup_state.language != language
+                                || setup_state.auto_detect != auto_detect
+                                || setup_state.profanity != profanity
+                                || setup_state.audio_device != audio_device
+                                || setup_state.replay != replay
+                            {
+                                info!("Recogniser setup changed, reconnecting");
+                                break Ok(RunState::Running);
+                            }
+                        }
+                    }
+
+                }
+            }
+        };
+
+        backend.disconnect(session).await;
+
+        match outcome {
+            Ok(RunState::Running) => {
+                setup_state.status.send_modify(|status| {
+                    status.connection = Connection::Connecting;
+                });
+            }
+            Ok(new_state) => {
+                info!("Recognition backend shut down");
+                return new_state;
+            }
+            Err(err) => {
+                error!("{err:?}");
+                // A session which stayed up for a while was working, even if
+                // nobody spoke
+                if connected_at.elapsed() >= HEALTHY_SESSION {
+                    backoff.reset();
+                }
+                if let Some(new_state) = back_off(
+                    err,
+                    &mut backoff,
+                    control_rx,
+                    setup_state,
+                    config,
+                    transcript,
+                )
+                .await
+                {
+                    return new_state;
+                }
+            }
+        }
+
+        info!("Reconnecting");
+    }
+}
+
+/// Report a failed session and wait before the next attempt. Gives the state
+/// to move to instead when the session should end, because the credentials
+/// were rejected, the attempts ran out or the controls asked for another
+/// state while waiting.
+async fn back_off(
+    err: Report,
+    backoff: &mut Backoff,
+    control_rx: &mut mpsc::Receiver<ControlMessage>,
+    setup_state: &mut SetupState,
+    config: &Config,
+    transcript: &mut Transcript,
+) -> Option<RunState> {
+    let last_error = Some(format!("{err:#}"));
+    let next = if err.downcast_ref::<AuthError>().is_some() {
+        error!("Not reconnecting as the credentials were rejected");
+        None
+    } else {
+        let next = backoff.next();
+        if next.is_none() {
+            error!("Giving up after {} attempts", backoff::MAX_ATTEMPTS);
+        }
+        next
+    };
+    let Some((attempt, delay)) = next else {
+        setup_state.status.send_modify(|status| {
+            status.connection = Connection::Failed;
+            status.last_error = last_error;
+        });
+        return Some(RunState::Stopped);
+    };
+
+    info!("Reconnecting in {delay:?} (attempt {attempt})");
+    let retry_at = Instant::now() + delay;
+    setup_state.status.send_modify(|status| {
+        status.connection = Connection::Reconnecting {
+            attempt,
+            retry_at: retry_at.into_std(),
+        };
+        status.last_error = last_error;
+    });
+
+    loop {
+        tokio::select! {
+            () = tokio::time::sleep_until(retry_at) => break,
+            msg = control_rx.recv() => match msg {
+                None => return Some(RunState::Stopped),
+                Some(ControlMessage::SetState(RunState::Running)) => {}
+                Some(ControlMessage::SetState(new_state)) => {
+                    return Some(new_state);
+                }
+                Some(ControlMessage::CorrectLine { id, text }) => {
+                    correct(transcript, id, text, setup_state, config);
+                }
+                // Setup changes are picked up by the next attempt
+                Some(other) => {
+                    handle_lang_and_wordlist(other, setup_state, config);
+                }
+            },
+        }
+    }
+
+    setup_state
+        .status
+        .send_modify(|status| status.connection = Connection::Connecting);
+    None
+}
+
+async fn run_test<T: Translator>(
+    tx: &mpsc::Sender<Line>,
+    control_rx: &mut mpsc::Receiver<ControlMessage>,
+    setup_state: &mut SetupState,
+    translator: Option<&Arc<T>>,
+    config: &Config,
+) -> RunState {
+    let test_data = match load_test_data(setup_state, config) {
+        Ok(test_data) => {
+            // Leave a failed Run's error on show alongside its failure
+            setup_state.status.send_modify(|status| {
+                if status.connection != Connection::Failed {
+                    status.last_error = None;
+                }
+            });
+            test_data
+        }
+        Err(err) => {
+            error!("Unable to load test data: {err:?}");
+            let last_error = Some(format!("Unable to load test data: {err:#}"));
+            setup_state
+                .status
+                .send_modify(|status| status.last_error = last_error);
+            return RunState::Stopped;
+        }
+    };
+    let entries = test_data.entries();
+    let mut start = Instant::now();
+    let mut position = 0;
+
+    loop {
+        let next = entries.get(position);
+        let due = start + next.map_or(Duration::ZERO, |(offset, _)| *offset);
+        tokio::select! {
+            () = tokio::time::sleep_until(due), if next.is_some() => {
+                let Some((_, line)) = next else { continue };
+                let mut line = line.clone();
+                post_process(&mut line, setup_state);
+                // Only fails once the GUI has gone
+                if tx.send(line.clone()).await.is_err() {
+                    break RunState::Stopped;
+                }
+                translate_later(line, setup_state, translator, tx);
+
+                position += 1;
+                if position == entries.len() {
+                    if setup_state.test_loop {
+                        position = 0;
+                        start += test_data.period();
+                    } else {
+                        info!("Test data finished");
+                    }
+                }
+            }
+            msg = control_rx.recv() => {
+                let Some(msg) = msg else {break RunState::Stopped};
+                match msg {
+                    ControlMessage::SetState(new_state) => {
+                        if new_state != RunState::Test {
+                            break new_state;
+                        }
+                    }
+                    other => {
+                        let file = setup_state.test_data.clone();
+                        handle_lang_and_wordlist(other, setup_state, config);
+                        if setup_state.test_data != file {
+                            info!("Test data changed, restarting");
+                            break RunState::Test;
+                        }
+                        // Looping was switched on after the end was reached
+                        if position == entries.len() && setup_state.test_loop {
+                            position = 0;
+                            start = Instant::now();
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn load_test_data(
+    setup_state: &SetupState,
+    config: &Config,
+) -> Result<TestData> {
+    let Some(file) = &setup_state.test_data else {
+        return TestData::builtin();
+    };
+    // Session logs may have been recorded next to the audio instead
+    let path = [
+        config.test_data_dir.as_deref(),
+        config.audio.recordings_dir.as_deref(),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|dir| dir.join(file.as_ref()))
+    .find(|path| path.exists())
+    .ok_or_else(|| eyre!("{file} not found"))?;
+    TestData::load(&path)
+}
+
+/// Apply the wordlist's corrections and profanity filter, and number
+/// finished lines so that their translations can follow them
+fn post_process(line: &mut Line, setup_state: &SetupState) {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+    setup_state.replacements.apply(line);
+    setup_state.blocklist.apply(setup_state.profanity, line);
+    if let Line::Recognised(utterance) = line {
+        utterance.id = Some(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    }
+}
+
+/// Translate a finished line in the background, sending the translation on
+/// once it arrives rather than holding up the captions behind it
+fn translate_later<T: Translator>(
+    line: Line,
+    setup_state: &SetupState,
+    translator: Option<&Arc<T>>,
+    tx: &mpsc::Sender<Line>,
+) {
+    let (Line::Recognised(utterance), Some(translator), Some(target)) =
+        (line, translator, &setup_state.translation)
+    else {
+        return;
+    };
+    let Some(id) = utterance.id else {
+        return;
+    };
+    let translator = Arc::clone(translator);
+    let target = target.clone();
+    let tx = tx.clone();
+    tokio::spawn(async move {
+        if let Some(text) =
+            translation::translate(&*translator, &utterance.text, &target).await
+        {
+            // Only fails once the GUI has gone
+            let _ = tx.send(Line::Translated { id, text }).await;
+        }
+    });
+}
+
+fn handle_lang_and_wordlist(
+    msg: ControlMessage,
+    setup_state: &mut SetupState,
+    config: &Config,
+) {
+    match msg {
+        ControlMessage::GetWordlist(reply) => {
+            let _ = reply.send(Wordlist {
+                options: list_wordlists(config),
+                current: setup_state.wordlist.clone(),
+            });
+        }
+        ControlMessage::SetWordlist(choice) => {
+            let options = list_wordlists(config);
+            if let Some(choice) = choice {
+                if options.contains(&choice) {
+                    setup_state.replacements =
+                        load_replacements(config, &choice);
+                    setup_state.wordlist = Some(choice);
+                } else {
+                    warn!("Invalid wordlist choice `{choice:?}`");
+                }
+            } else {
+                setup_state.wordlist = None;
+                setup_state.replacements = Replacements::default();
+            }
+        }
+        ControlMessage::SetLanguage(language) => {
+            if config.languages.contains(&language) {
+                setup_state.language = language;
+            } else {
+                warn!("Invalid language choice `{language:?}`");
+            }
+        }
+        ControlMessage::SetAutoDetect(enabled) => {
+            setup_state.auto_detect = enabled;
+        }
+        ControlMessage::SetProfanity(mode) => setup_state.profanity = mode,
+        ControlMessage::SetAudioDevice(device) => {
+            setup_state.audio_device = device;
+        }
+        ControlMessage::SetReplay(replay) => match replay {
+            Some(replay) => match resolve_replay(config, &replay.source) {
+                Some(source) => {
+                    setup_state.replay = Some(Replay { source, ..replay });
+                }
+                None => warn!("Invalid replay choice `{:?}`", replay.source),
+            },
+            None => setup_state.replay = None,
+        },
+        ControlMessage::SetTestData(choice) => match choice {
+            Some(choice) if !list_test_data(config).contains(&choice) => {
+                warn!("Invalid test data choice `{choice:?}`");
+            }
+            choice => setup_state.test_data = choice,
+        },
+        ControlMessage::SetTestLoop(enabled) => setup_state.test_loop = enabled,
+        ControlMessage::SetTranslation(target) => match target {
+            Some(target)
+                if !config.translation_languages().contains(&target) =>
+            {
+                warn!("Invalid translation language `{target:?}`");
+            }
+            target => setup_state.translation = target,
+        },
+        // Lines released after their run ended
+        ControlMessage::CorrectLine { id, text } => {
+            if let Some(transcript) = &mut setup_state.last_transcript
+                && transcript.correct(id, text)
+            {
+                save_transcript(transcript, config);
+            }
+        }
+        other => panic!("Unreachable: {other:?}"),
+    }
+}
+
+/// Correct a line in the running transcript, or in the last run's if it
+/// was still being moderated when that ended
+fn correct(
+    transcript: &mut Transcript,
+    id: u64,
+    text: Option<String>,
+    setup_state: &mut SetupState,
+    config: &Config,
+) {
+    if !transcript.correct(id, text.clone()) {
+        let msg = ControlMessage::CorrectLine { id, text };
+        handle_lang_and_wordlist(msg, setup_state, config);
+    }
+}
+
+fn save_transcript(transcript: &Transcript, config: &Config) {
+    if let Some(transcript_dir) = &config.transcript_dir
+        && let Err(err) = transcript.save(transcript_dir)
+    {
+        error!("Failed to save transcript: {err:?}");
+    }
+}
+
+/// Path of a recording from the recordings directory, or a stream URL as is
+fn resolve_replay(config: &Config, source: &str) -> Option<Arc<str>> {
+    if source.contains("://") {
+        return Some(source.into());
+    }
+    let recordings_dir = config.audio.recordings_dir.as_deref()?;
+    crate::list_directory(recordings_dir)
+        .iter()
+        .any(|name| name.as_ref() == source)
+        .then(|| recordings_dir.join(source).to_string_lossy().into())
+}
+
+/// Wordlists in the wordlist directory, excluding their replacement rules
+fn list_wordlists(config: &Config) -> Vec<Arc<str>> {
+    config
+        .wordlist_dir
+        .as_deref()
+        .map(crate::list_directory)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|name| !name.ends_with(replacements::SUFFIX))
+        .collect()
+}
+
+fn list_test_data(config: &Config) -> Vec<Arc<str>> {
+    config
+        .test_data_dir
+        .as_deref()
+        .map(crate::list_directory)
+        .unwrap_or_default()
+}
+
+fn load_replacements(config: &Config, wordlist: &str) -> Replacements {
+    let Some(wordlist_dir) = &config.wordlist_dir else {
+        return Replacements::default();
+    };
+    Replacements::for_wordlist(&wordlist_dir.join(wordlist))
+        .inspect_err(|err| error!("{err:?}"))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        mock::{MockBackend, MockConnection, MockEvent, MockTranslator},
+        *,
+    };
+    use crate::{
+        Utterance,
+        config::{AudioConfig, TranslatorConfig},
+    };
+    use pretty_assertions::assert_eq;
+    use std::collections::HashMap;
+    use tokio::{sync::oneshot, task::JoinHandle, time::timeout};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    struct Harness {
+        rx: mpsc::Receiver<Line>,
+        control_tx: mpsc::Sender<ControlMessage>,
+        backend: MockBackend,
+        status: watch::Receiver<Status>,
+        task: JoinHandle<Result<()>>,
+    }
+
+    impl Harness {
+        fn start(
+            connections: impl IntoIterator<Item = MockConnection>,
+        ) -> Self {
+            Self::with_translator(connections, None)
+        }
+
+        fn with_translator(
+            connections: impl IntoIterator<Item = MockConnection>,
+            translator: Option<MockTranslator>,
+        ) -> Self {
+            let (tx, rx) = mpsc::channel(10);
+            let (control_tx, control_rx) = mpsc::channel(5);
+            let backend = MockBackend::new(connections);
+            let (status_tx, status) = watch::channel(Status::default());
+            let task = tokio::task::spawn(start_inner(
+                tx,
+                control_rx,
+                watch::channel(None).0,
+                status_tx,
+                backend.clone(),
+                translator,
+                Config {
+                    languages: vec!["en-GB".into(), "ja-JP".into()],
+                    translator: Some(TranslatorConfig {
+                        region: String::new(),
+                        key: String::new(),
+                        endpoint: String::new(),
+                        languages: vec!["ja".into()],
+                    }),
+                    ..Default::default()
+                },
+            ));
+            Self {
+                rx,
+                control_tx,
+                backend,
+                status,
+                task,
+            }
+        }
+
+        async fn send(&self, msg: ControlMessage) {
+            self.control_tx.send(msg).await.unwrap();
+        }
+
+        /// Receive a line without its id, as the ids are shared between
+        /// listeners and so depend on the order the tests run in
+        async fn recv(&mut self) -> Line {
+            self.recv_numbered().await.1
+        }
+
+        async fn recv_numbered(&mut self) -> (Option<u64>, Line) {
+            let mut line =
+                timeout(TIMEOUT, self.rx.recv()).await.unwrap().unwrap();
+            let id = line
+                .utterance_mut()
+                .and_then(|utterance| utterance.id.take());
+            (id, line)
+        }
+
+        async fn get_wordlist(&self) -> Wordlist {
+            let (tx, rx) = oneshot::channel();
+            self.send(ControlMessage::GetWordlist(tx)).await;
+            timeout(TIMEOUT, rx).await.unwrap().unwrap()
+        }
+
+        async fn wait_for(&self, condition: impl Fn(&MockBackend) -> bool) {
+            timeout(TIMEOUT, async {
+                while !condition(&self.backend) {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .unwrap();
+        }
+
+        async fn wait_for_status(&self, condition: impl Fn(&Status) -> bool) {
+            timeout(TIMEOUT, async {
+                while !condition(&self.status.borrow()) {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .unwrap();
+        }
+    }
+
+    impl Drop for Harness {
+        fn drop(&mut self) {
+            self.task.abort();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_forwards_lines() {
+        let mut harness = Harness::start([MockConnection::Accept(vec![
+            MockEvent::Recognising("hello"),
+            MockEvent::Recognised("hello world"),
+        ])]);
+
+        harness
+            .send(ControlMessage::SetState(RunState::Running))
+            .await;
+
+        assert_eq!(harness.recv().await, Line::Recognising("hello".into()));
+        assert_eq!(
+            harness.recv().await,
+            Line::Recognised("hello world".into())
+        );
+        assert_eq!(harness.backend.connects(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_reconnects_after_drop() {
+        let mut harness = Harness::start([
+            MockConnection::Accept(vec![
+                MockEvent::Recognised("one"),
+                MockEvent::Drop,
+            ]),
+            MockConnection::Accept(vec![MockEvent::Recognised("two")]),
+        ]);
+
+        harness
+            .send(ControlMessage::SetState(RunState::Running))
+            .await;
+
+        assert_eq!(harness.recv().await, Line::Recognised("one".into()));
+        assert_eq!(harness.recv().await, Line::Recognised("two".into()));
+        assert_eq!(harness.backend.connects(), 2);
+        assert_eq!(harness.backend.disconnects(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_auth_error_stops() {
+        let mut harness = Harness::start([MockConnection::AuthError]);
+
+        harness
+            .send(ControlMessage::SetState(RunState::Running))
+            .await;
+        harness
+            .wait_for_status(|status| status.connection == Connection::Failed)
+            .await;
+        let status = harness.status.borrow().clone();
+        assert_eq!(status.run_state, RunState::Stopped);
+        assert_eq!(
+            status.last_error.as_deref(),
+            Some("Authentication failed: 401 Unauthorized")
+        );
+
+        // The listener should have dropped back to Stopped without retrying
+        // and still be accepting control messages
+        harness.get_wordlist().await;
+        harness.send(ControlMessage::SetState(RunState::Test)).await;
+        harness.recv().await;
+        assert_eq!(harness.backend.connects(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_retries_unreachable() {
+        let mut harness = Harness::start([
+            MockConnection::Unreachable,
+            MockConnection::Accept(vec![MockEvent::Recognised("hello")]),
+        ]);
+
+        harness
+            .send(ControlMessage::SetState(RunState::Running))
+            .await;
+        harness
+            .wait_for_status(|status| {
+                matches!(
+                    status.connection,
+                    Connection::Reconnecting { attempt: 1, .. }
+                )
+            })
+            .await;
+
+        assert_eq!(harness.recv().await, Line::Recognised("hello".into()));
+        assert_eq!(harness.backend.connects(), 2);
+        let status = harness.status.borrow().clone();
+        assert_eq!(status.run_state, RunState::Running);
+        assert_eq!(status.connection, Connection::Listening);
+        assert_eq!(status.last_error.as_deref(), Some("Connection refused"));
+    }
+
+    #[tokio::test]
+    async fn test_stop_disconnects() {
+        let mut harness = Harness::start([MockConnection::Accept(vec![
+            MockEvent::Recognised("one"),
+        ])]);
+
+        harness
+            .send(ControlMessage::SetState(RunState::Running))
+            .await;
+        assert_eq!(harness.recv().await, Line::Recognised("one".into()));
+
+        harness
+            .send(ControlMessage::SetState(RunState::Stopped))
+            .await;
+        harness.wait_for(|backend| backend.disconnects() == 1).await;
+        assert_eq!(harness.backend.connects(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_control_messages_while_running() {
+        let mut harness = Harness::start([MockConnection::Accept(vec![
+            MockEvent::Recognised("one"),
+        ])]);
+
+        harness
+            .send(ControlMessage::SetState(RunState::Running))
+            .await;
+        assert_eq!(harness.recv().await, Line::Recognised("one".into()));
+
+        harness
+            .send(ControlMessage::SetState(RunState::Running))
+            .await;
+        harness.send(ControlMessage::SetWordlist(None)).await;
+        assert_eq!(
+            harness.get_wordlist().await,
+            Wordlist {
+                options: Vec::new(),
+                current: None,
+            }
+        );
+        assert_eq!(harness.backend.connects(), 1);
+        assert_eq!(harness.backend.disconnects(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_language_change_reconnects() {
+        let mut harness = Harness::start([
+            MockConnection::Accept(vec![MockEvent::Recognised("hello")]),
+            MockConnection::Accept(vec![MockEvent::Recognised("konnichiwa")]),
+        ]);
+
+        harness
+            .send(ControlMessage::SetState(RunState::Running))
+            .await;
+        assert_eq!(harness.recv().await, Line::Recognised("hello".into()));
+
+        // Unknown languages are ignored
+        harness
+            .send(ControlMessage::SetLanguage("xx-XX".into()))
+            .await;
+        harness
+            .send(ControlMessage::SetLanguage("en-GB".into()))
+            .await;
+        harness.get_wordlist().await;
+        assert_eq!(harness.backend.connects(), 1);
+
+        harness
+            .send(ControlMessage::SetLanguage("ja-JP".into()))
+            .await;
+        assert_eq!(harness.recv().await, Line::Recognised("konnichiwa".into()));
+        assert_eq!(harness.backend.connects(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_auto_detect_reconnects() {
+        let mut harness = Harness::start([
+            MockConnection::Accept(vec![MockEvent::Recognised("hello")]),
+            MockConnection::Accept(vec![MockEvent::RecognisedIn(
+                "ja-JP",
+                "konnichiwa",
+            )]),
+        ]);
+
+        harness
+            .send(ControlMessage::SetState(RunState::Running))
+            .await;
+        assert_eq!(harness.recv().await, Line::Recognised("hello".into()));
+
+        harness.send(ControlMessage::SetAutoDetect(true)).await;
+        assert_eq!(
+            harness.recv().await,
+            Line::Recognised(Utterance {
+                language: Some("ja-JP".into()),
+                ..Utterance::from("konnichiwa")
+            })
+        );
+        assert_eq!(harness.backend.auto_detect(), [false, true]);
+    }
+
+    #[test]
+    fn test_missing_test_data_dir() {
+        let config = Config {
+            test_data_dir: Some("/nonexistent/captioninator".into()),
+            ..Default::default()
+        };
+        let mut setup_state = SetupState::new(
+            &config,
+            watch::channel(None).0,
+            watch::channel(Status::default()).0,
+        );
+
+        // A choice saved from before the directory went is ignored, leaving
+        // the built-in lines
+        assert!(list_test_data(&config).is_empty());
+        handle_lang_and_wordlist(
+            ControlMessage::SetTestData(Some("session.txt".into())),
+            &mut setup_state,
+            &config,
+        );
+        assert_eq!(setup_state.test_data, None);
+        assert_eq!(
+            load_test_data(&setup_state, &config).unwrap().entries(),
+            TestData::builtin().unwrap().entries()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_invalid_test_data() {
+        let dir = std::env::temp_dir()
+            .join(format!("captioninator-test-data-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("broken.txt"), "not a line\n").unwrap();
+        let config = Config {
+            test_data_dir: Some(dir.clone()),
+            ..Default::default()
+        };
+        let (status_tx, status) = watch::channel(Status::default());
+        let mut setup_state =
+            SetupState::new(&config, watch::channel(None).0, status_tx);
+        handle_lang_and_wordlist(
+            ControlMessage::SetTestData(Some("broken.txt".into())),
+            &mut setup_state,
+            &config,
+        );
+
+        let (tx, _rx) = mpsc::channel(1);
+        let (_control_tx, mut control_rx) = mpsc::channel(1);
+        let run_state = run_test(
+            &tx,
+            &mut control_rx,
+            &mut setup_state,
+            None::<&Arc<MockTranslator>>,
+            &config,
+        )
+        .await;
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // The operator is told why nothing is playing
+        assert_eq!(run_state, RunState::Stopped);
+        let last_error = status.borrow().last_error.clone().unwrap();
+        assert!(
+            last_error
+                .starts_with("Unable to load test data: Invalid test data"),
+            "{last_error}"
+        );
+        assert!(
+            last_error.ends_with("Line 1: Invalid input"),
+            "{last_error}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_session_log_beside_audio() {
+        let dir = std::env::temp_dir()
+            .join(format!("captioninator-session-{}", std::process::id()));
+        let config = Config {
+            test_data_dir: Some(dir.join("test-data")),
+            record_sessions: true,
+            audio: AudioConfig {
+                recordings_dir: Some(dir.join("recordings")),
+                record: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut setup_state = SetupState::new(
+            &config,
+            watch::channel(None).0,
+            watch::channel(Status::default()).0,
+        );
+        let backend = MockBackend::new([MockConnection::Accept(vec![
+            MockEvent::Recognised("hello world"),
+        ])]);
+        let (tx, mut rx) = mpsc::channel(1);
+        let (control_tx, mut control_rx) = mpsc::channel(1);
+        let mut transcript = Transcript::start();
+        let (run_state, ()) = tokio::join!(
+            do_run(
+                &tx,
+                &mut control_rx,
+                &mut setup_state,
+                &backend,
+                None::<&Arc<MockTranslator>>,
+                &config,
+                &mut transcript,
+            ),
+            async {
+                rx.recv().await.unwrap();
+                control_tx
+                    .send(ControlMessage::SetState(RunState::Stopped))
+                    .await
+                    .unwrap();
+            },
+        );
+        let recordings = crate::list_directory(&dir.join("recordings"));
+        let test_data = dir.join("test-data").exists();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // The log takes the name the audio is recorded under
+        assert_eq!(run_state, RunState::Stopped);
+        let name = setup_state.session_name.unwrap();
+        assert_eq!(recordings, [Arc::from(format!("{name}.jsonl"))]);
+        assert!(!test_data);
+    }
+
+    #[test]
+    fn test_late_correction() {
+        let dir = std::env::temp_dir()
+            .join(format!("captioninator-transcript-{}", std::process::id()));
+        let config = Config {
+            transcript_dir: Some(dir.clone()),
+            ..Default::default()
+        };
+        let mut setup_state = SetupState::new(
+            &config,
+            watch::channel(None).0,
+            watch::channel(Status::default()).0,
+        );
+        let mut transcript = Transcript::start();
+        transcript.record(&Line::Recognised(Utterance {
+            id: Some(7),
+            ..Utterance::from("unmoderated")
+        }));
+        save_transcript(&transcript, &config);
+        setup_state.last_transcript = Some(transcript);
+
+        // The line is corrected once it leaves the moderation queue, after
+        // the run has stopped
+        handle_lang_and_wordlist(
+            ControlMessage::CorrectLine {
+                id: 7,
+                text: Some("moderated".into()),
+            },
+            &mut setup_state,
+            &config,
+        );
+        let files = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| std::fs::read_to_string(entry.unwrap().path()))
+            .collect::<std::io::Result<Vec<_>>>();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let files = files.unwrap();
+        assert_eq!(files.len(), 2);
+        for file in files {
+            assert!(file.contains("moderated"));
+            assert!(!file.contains("unmoderated"), "{file}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_translation() {
+        let mut harness = Harness::with_translator(
+            [MockConnection::Accept(vec![
+                MockEvent::Recognising("hello"),
+                MockEvent::Recognised("hello world"),
+                MockEvent::Recognised("goodbye"),
+            ])],
+            Some(MockTranslator),
+        );
+
+        // Unknown targets are ignored
+        harness
+            .send(ControlMessage::SetTranslation(Some("xx".into())))
+            .await;
+        harness
+            .send(ControlMessage::SetTranslation(Some("ja".into())))
+            .await;
+        harness
+            .send(ControlMessage::SetState(RunState::Running))
+            .await;
+
+        // Only finished lines are translated, with each translation
+        // following its line
+        assert_eq!(harness.recv().await, Line::Recognising("hello".into()));
+        let mut ids = HashMap::new();
+        let mut translations = HashMap::new();
+        for _ in 0..4 {
+            match harness.recv_numbered().await {
+                (Some(id), Line::Recognised(utterance)) => {
+                    assert!(!translations.contains_key(&id));
+                    ids.insert(utterance.text, id);
+                }
+                (None, Line::Translated { id, text }) => {
+                    translations.insert(id, text);
+                }
+                other => panic!("Unexpected line {other:?}"),
+            }
+        }
+        assert_eq!(
+            translations,
+            HashMap::from([
+                (ids["hello world"], "[ja] hello world".into()),
+                (ids["goodbye"], "[ja] goodbye".into()),
+            ])
+        );
+    }
+}
@@ -0,0 +1,314 @@
+//! In-process capture through cpal, so that ffmpeg need not be installed
+
+use super::audio::{AudioEncoding, AudioLevel, wav_header};
+use crate::{Result, config::AudioConfig};
+use color_eyre::eyre::eyre;
+use cpal::{
+    FromSample, SizedSample,
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+};
+use std::{collections::VecDeque, sync::Arc};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio_stream::wrappers::ReceiverStream;
+
+/// Rate the recognisers are given audio at
+const SAMPLE_RATE: u32 = 16_000;
+/// Samples sent to the recogniser at a time, 100 ms worth
+const CHUNK_SAMPLES: usize = SAMPLE_RATE as usize / 10;
+
+pub async fn capture(
+    config: &AudioConfig,
+    device: Option<&str>,
+    encoding: AudioEncoding,
+    levels: watch::Sender<Option<AudioLevel>>,
+) -> Result<ReceiverStream<Vec<u8>>> {
+    let (tx, rx) = mpsc::channel(10);
+    match encoding {
+        AudioEncoding::Pcm16kMono => {}
+        AudioEncoding::Wav16kMono => tx.send(wav_header()).await?,
+        AudioEncoding::WebmOpus => {
+            return Err(eyre!("Native audio capture cannot encode WebM"));
+        }
+    }
+
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let config = config.clone();
+    let device = device.map(String::from);
+    let handle = tokio::runtime::Handle::current();
+    // cpal streams cannot be moved between threads, so each capture gets a
+    // thread of its own which keeps the stream open until the session stops
+    // reading
+    std::thread::spawn(move || {
+        let stream = match start_stream(
+            &config,
+            device.as_deref(),
+            tx.clone(),
+            levels.clone(),
+        ) {
+            Ok(stream) => stream,
+            Err(err) => {
+                let _ = ready_tx.send(Err(err));
+                return;
+            }
+        };
+        let _ = ready_tx.send(Ok(()));
+
+        handle.block_on(tx.closed());
+        drop(stream);
+        levels.send_replace(None);
+        info!("Audio capture stopped");
+    });
+    ready_rx.await??;
+
+    Ok(ReceiverStream::new(rx))
+}
+
+/// Capture devices known to the system audio API
+pub fn list_devices() -> Vec<Arc<str>> {
+    match cpal::default_host().input_devices() {
+        Ok(devices) => devices
+            .filter_map(|device| device.name().ok())
+            .map(Into::into)
+            .collect(),
+        Err(err) => {
+            warn!("Unable to list audio inputs: {err}");
+            Vec::new()
+        }
+    }
+}
+
+fn start_stream(
+    config: &AudioConfig,
+    device: Option<&str>,
+    tx: mpsc::Sender<Vec<u8>>,
+    levels: watch::Sender<Option<AudioLevel>>,
+) -> Result<cpal::Stream> {
+    let host = cpal::default_host();
+    let device = match device.or(config.device.as_deref()) {
+        Some(name) => host
+            .input_devices()?
+            .find(|device| device.name().is_ok_and(|other| other == name))
+            .ok_or_else(|| eyre!("No audio input named `{name}`"))?,
+        None => host
+            .default_input_device()
+            .ok_or_else(|| eyre!("No default audio input"))?,
+    };
+    let supported = device.default_input_config()?;
+    let stream_config = supported.config();
+    let converter = Converter::new(
+        config,
+        stream_config.channels,
+        stream_config.sample_rate.0,
+    );
+    info!(
+        "Capturing from {} at {} Hz",
+        device.name().unwrap_or_default(),
+        stream_config.sample_rate.0
+    );
+
+    let stream = match supported.sample_format() {
+        cpal::SampleFormat::F32 => {
+            build_stream::<f32>(&device, &stream_config, converter, tx, levels)
+        }
+        cpal::SampleFormat::I16 => {
+            build_stream::<i16>(&device, &stream_config, converter, tx, levels)
+        }
+        cpal::SampleFormat::U16 => {
+            build_stream::<u16>(&device, &stream_config, converter, tx, levels)
+        }
+        other => Err(eyre!("Unsupported sample format {other:?}")),
+    }?;
+    stream.play()?;
+    Ok(stream)
+}
+
+fn build_stream<T>(
+    device: &cpal::Device,
+    stream_config: &cpal::StreamConfig,
+    mut converter: Converter,
+    tx: mpsc::Sender<Vec<u8>>,
+    levels: watch::Sender<Option<AudioLevel>>,
+) -> Result<cpal::Stream>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let mut pending = Vec::with_capacity(CHUNK_SAMPLES * 2);
+    let stream = device.build_input_stream(
+        stream_config,
+        move |data: &[T], _: &cpal::InputCallbackInfo| {
+            let samples = data
+                .iter()
+                .map(|sample| sample.to_sample::<f32>())
+                .collect::<Vec<_>>();
+            pending.extend(converter.convert(&samples));
+            if pending.len() < CHUNK_SAMPLES {
+                return;
+            }
+
+            levels.send_replace(Some(measure(&pending)));
+            // The callback must not block, so audio is dropped if the
+            // recogniser falls behind
+            if tx.try_send(encode(&pending)).is_err() {
+                warn!("Audio channel full");
+            }
+            pending.clear();
+        },
+        |err| error!("Audio capture failed: {err}"),
+        None,
+    )?;
+    Ok(stream)
+}
+
+/// Turns interleaved frames at the device's rate into the 16 kHz mono the
+/// recognisers expect, applying the channel selection and gain from the
+/// config
+struct Converter {
+    channels: usize,
+    channel: Option<usize>,
+    gain: f32,
+    /// Input frames per output sample
+    step: f64,
+    /// Position of the next output sample, in input frames from the start of
+    /// the next call
+    position: f64,
+    /// Last frame of the previous call, to interpolate from
+    previous: f32,
+    /// Latest frames, averaged across the input frames each output sample
+    /// covers when downsampling, so that anything above 8 kHz doesn't alias
+    /// into the speech band
+    window: VecDeque<f32>,
+}
+
+impl Converter {
+    fn new(config: &AudioConfig, channels: u16, sample_rate: u32) -> Self {
+        let step = f64::from(sample_rate) / f64::from(SAMPLE_RATE);
+        let window_len = (step.round() as usize).max(1);
+        Self {
+            channels: usize::from(channels.max(1)),
+            // Channels are counted from 1 in the config, as on a mixer
+            channel: config
+                .channel
+                .map(|channel| usize::from(channel.saturating_sub(1))),
+            gain: 10_f32.powf(config.gain_db / 20.0),
+            step,
+            position: 0.0,
+            previous: 0.0,
+            window: std::iter::repeat_n(0.0, window_len).collect(),
+        }
+    }
+
+    /// Average of the frame with those before it across the window
+    fn filter(&mut self, frame: f32) -> f32 {
+        self.window.pop_front();
+        self.window.push_back(frame);
+        self.window.iter().sum::<f32>() / self.window.len() as f32
+    }
+
+    fn convert(&mut self, samples: &[f32]) -> Vec<f32> {
+        let (channel, gain) = (self.channel, self.gain);
+        let frames = samples.chunks_exact(self.channels).map(|frame| {
+            let sample = match channel {
+                Some(channel) => frame.get(channel).copied().unwrap_or(0.0),
+                None => frame.iter().sum::<f32>() / frame.len() as f32,
+            };
+            sample * gain
+        });
+
+        // Linear interpolation, which is plenty for speech once filtered
+        let mut output = Vec::new();
+        let mut previous = self.previous;
+        let mut count = 0;
+        for (index, frame) in frames.enumerate() {
+            let current = self.filter(frame);
+            while self.position <= index as f64 {
+                let fraction = (self.position - index as f64 + 1.0) as f32;
+                output.push(previous + (current - previous) * fraction);
+                self.position += self.step;
+            }
+            previous = current;
+            count = index + 1;
+        }
+        self.position -= count as f64;
+        self.previous = previous;
+        output
+    }
+}
+
+/// Level of samples in the range -1 to 1, in dBFS
+fn measure(samples: &[f32]) -> AudioLevel {
+    let peak = samples
+        .iter()
+        .fold(0.0_f32, |peak, sample| peak.max(sample.abs()));
+    let mean_square = samples.iter().map(|sample| sample * sample).sum::<f32>()
+        / samples.len().max(1) as f32;
+    AudioLevel {
+        peak: 20.0 * peak.log10(),
+        rms: 10.0 * mean_square.log10(),
+    }
+}
+
+/// Signed 16-bit little-endian PCM
+fn encode(samples: &[f32]) -> Vec<u8> {
+    samples
+        .iter()
+        .flat_map(|sample| {
+            ((sample.clamp(-1.0, 1.0) * f32::from(i16::MAX)) as i16)
+                .to_le_bytes()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_convert() {
+        let config = AudioConfig {
+            channel: Some(2),
+            ..Default::default()
+        };
+        // Stereo at 48 kHz, with only the second channel carrying a ramp
+        let mut converter = Converter::new(&config, 2, 48_000);
+        let samples = (0..12)
+            .flat_map(|frame| [0.5, frame as f32 / 8.0])
+            .collect::<Vec<_>>();
+
+        // Every third frame, averaged with the two before it and carrying on
+        // across calls
+        assert_eq!(converter.convert(&samples[..10]), [0.0, 0.25]);
+        assert_eq!(converter.convert(&samples[10..]), [0.625, 1.0]);
+    }
+
+    #[test]
+    fn test_ratio() {
+        let mut converter = Converter::new(&AudioConfig::default(), 1, 44_100);
+        let output = (0..100)
+            .map(|_| converter.convert(&[0.5; 441]).len())
+            .sum::<usize>();
+        assert_eq!(output, 16_000);
+    }
+
+    #[test]
+    fn test_anti_alias() {
+        // A 16 kHz tone at 48 kHz, which taking every third frame would turn
+        // into a constant
+        let mut converter = Converter::new(&AudioConfig::default(), 1, 48_000);
+        let tone = (0..4800)
+            .map(|frame| (frame as f32 * std::f32::consts::TAU / 3.0).cos())
+            .collect::<Vec<_>>();
+        let output = converter.convert(&tone);
+        assert_eq!(output.len(), 1600);
+        // Once the window has filled
+        assert!(output[1..].iter().all(|sample| sample.abs() < 1e-3));
+    }
+
+    #[test]
+    fn test_upsample() {
+        let mut converter = Converter::new(&AudioConfig::default(), 1, 8_000);
+        assert_eq!(converter.convert(&[0.25, 0.5]), [0.25, 0.375, 0.5]);
+        assert_eq!(converter.convert(&[0.75]), [0.625, 0.75]);
+    }
+}
@@ -0,0 +1,97 @@
+//! Local profanity filtering, so that every backend honours the chosen
+//! [`ProfanityMode`] even if it has no filter of its own
+//!
+//! The blocklist is a text file with one word or phrase per line, matched
+//! case-insensitively. Lines starting with `#` are comments.
+
+use super::replacements::bounded;
+use crate::{Line, ProfanityMode, Result};
+use regex::{Captures, Regex};
+use std::{borrow::Cow, path::Path};
+
+#[derive(Default)]
+pub struct Blocklist {
+    pattern: Option<Regex>,
+}
+
+impl Blocklist {
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    fn parse(content: &str) -> Result<Self> {
+        let words = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(bounded)
+            .collect::<Vec<_>>();
+        if words.is_empty() {
+            return Ok(Self::default());
+        }
+
+        Ok(Self {
+            pattern: Some(Regex::new(&format!("(?i){}", words.join("|")))?),
+        })
+    }
+
+    pub fn apply(&self, mode: ProfanityMode, line: &mut Line) {
+        let (Some(pattern), Some(utterance)) =
+            (&self.pattern, line.utterance_mut())
+        else {
+            return;
+        };
+        let text = &mut utterance.text;
+        let filtered = match mode {
+            ProfanityMode::Raw => return,
+            // Matches the asterisks used by Azure's own masking
+            ProfanityMode::Masked => pattern
+                .replace_all(text, |captures: &Captures| {
+                    "*".repeat(captures[0].chars().count())
+                }),
+            ProfanityMode::Removed => pattern.replace_all(text, ""),
+        };
+        if let Cow::Owned(filtered) = filtered {
+            *text = if mode == ProfanityMode::Removed {
+                filtered.split_whitespace().collect::<Vec<_>>().join(" ")
+            } else {
+                filtered
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn apply(blocklist: &Blocklist, mode: ProfanityMode, text: &str) -> String {
+        let mut line = Line::Recognised(text.into());
+        blocklist.apply(mode, &mut line);
+        line.utterance_mut().unwrap().text.clone()
+    }
+
+    #[test]
+    fn test_modes() {
+        let blocklist =
+            Blocklist::parse("# comment\ndarn\n\nflipping heck\n").unwrap();
+        let text = "Darn it, flipping heck. Darning socks";
+
+        assert_eq!(apply(&blocklist, ProfanityMode::Raw, text), text);
+        assert_eq!(
+            apply(&blocklist, ProfanityMode::Masked, text),
+            "**** it, *************. Darning socks"
+        );
+        assert_eq!(
+            apply(&blocklist, ProfanityMode::Removed, text),
+            "it, . Darning socks"
+        );
+    }
+
+    #[test]
+    fn test_empty() {
+        let blocklist = Blocklist::parse("# nothing here\n").unwrap();
+        assert_eq!(apply(&blocklist, ProfanityMode::Removed, "darn"), "darn");
+    }
+}
@@ -0,0 +1,91 @@
+//! Recordings of Run sessions, saved as test data so that the Test mode can
+//! replay exactly what the recogniser produced
+
+use super::test_data::Entry;
+use crate::{Line, Result, transcript::iso_timestamp};
+use std::{
+    fs::File,
+    io::{LineWriter, Write},
+    path::{Path, PathBuf},
+    time::{Instant, SystemTime},
+};
+
+pub struct Recording {
+    file: LineWriter<File>,
+    path: PathBuf,
+    started: Instant,
+    /// Set after a failed write, so that a full disk is reported once rather
+    /// than for every line
+    failed: bool,
+}
+
+impl Recording {
+    /// Start a recording in `dir` named `name`, the same as the session's
+    /// audio recording
+    pub fn start(dir: &Path, name: &str) -> Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(format!("{name}.jsonl"));
+        let file = LineWriter::new(File::create(&path)?);
+        info!("Recording session to {}", path.display());
+        Ok(Self {
+            file,
+            path,
+            started: Instant::now(),
+            failed: false,
+        })
+    }
+
+    /// Record a line as it came from the recogniser, before any corrections
+    /// or filtering
+    pub fn record(&mut self, line: &Line) {
+        if self.failed {
+            return;
+        }
+        let entry = Entry {
+            offset: self.started.elapsed().as_secs_f64(),
+            time: Some(iso_timestamp(SystemTime::now())),
+            line: line.clone(),
+        };
+        let result = serde_json::to_string(&entry)
+            .map_err(Into::into)
+            .and_then(|json| writeln!(self.file, "{json}"));
+        if let Err(err) = result {
+            error!("Unable to record to {}: {err}", self.path.display());
+            self.failed = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::listener::test_data::TestData;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_replayable() {
+        let dir = std::env::temp_dir()
+            .join(format!("captioninator-recording-{}", std::process::id()));
+        let mut recording = Recording::start(&dir, "session").unwrap();
+        let lines = [
+            Line::Recognising("hello".into()),
+            Line::Recognised("Hello there.".into()),
+        ];
+        for line in &lines {
+            recording.record(line);
+        }
+        let path = recording.path.clone();
+        drop(recording);
+
+        let test_data = TestData::load(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            test_data
+                .entries()
+                .iter()
+                .map(|(_, line)| line.clone())
+                .collect::<Vec<_>>(),
+            lines
+        );
+    }
+}
@@ -0,0 +1,193 @@
+//! Post-processing rules which correct commonly mis-recognised words
+//!
+//! Rules are loaded from `<wordlist>.replacements.toml` next to the active
+//! wordlist and are applied in order: words, then phrases, then regexes.
+//!
+//! ```toml
+//! # Whole words, matched case-sensitively
+//! [words]
+//! Jhon = "John"
+//!
+//! # Phrases, matched case-insensitively
+//! [phrases]
+//! "holy spirit" = "Holy Spirit"
+//!
+//! # Regular expressions. The replacement may refer to capture groups.
+//! [regex]
+//! '\bpsalm (\d+)' = "Psalm $1"
+//! ```
+
+use crate::{Line, Result};
+use color_eyre::eyre::WrapErr;
+use regex::{NoExpand, Regex};
+use serde::Deserialize;
+use std::{borrow::Cow, collections::BTreeMap, path::Path};
+
+pub const SUFFIX: &str = ".replacements.toml";
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    words: BTreeMap<String, String>,
+    #[serde(default)]
+    phrases: BTreeMap<String, String>,
+    #[serde(default)]
+    regex: BTreeMap<String, String>,
+}
+
+struct Rule {
+    pattern: Regex,
+    replacement: String,
+    /// Whether `$1` etc. in the replacement refer to capture groups
+    expand: bool,
+}
+
+#[derive(Default)]
+pub struct Replacements {
+    rules: Vec<Rule>,
+}
+
+impl Replacements {
+    /// Load the rules for a wordlist, if it has any
+    pub fn for_wordlist(wordlist_path: &Path) -> Result<Self> {
+        let mut path = wordlist_path.as_os_str().to_owned();
+        path.push(SUFFIX);
+        let path = Path::new(&path);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let content = std::fs::read_to_string(path)?;
+        Self::parse(&content)
+            .wrap_err_with(|| format!("Invalid rules in {}", path.display()))
+    }
+
+    fn parse(content: &str) -> Result<Self> {
+        let file: RulesFile = toml::de::from_str(content)?;
+        let mut rules = Vec::new();
+
+        for (word, replacement) in file.words {
+            rules.push(Rule {
+                pattern: Regex::new(&bounded(&word))?,
+                replacement,
+                expand: false,
+            });
+        }
+        for (phrase, replacement) in file.phrases {
+            rules.push(Rule {
+                pattern: Regex::new(&format!("(?i){}", bounded(&phrase)))?,
+                replacement,
+                expand: false,
+            });
+        }
+        for (pattern, replacement) in file.regex {
+            rules.push(Rule {
+                pattern: Regex::new(&pattern)?,
+                replacement,
+                expand: true,
+            });
+        }
+
+        Ok(Self { rules })
+    }
+
+    pub fn apply(&self, line: &mut Line) {
+        let Some(utterance) = line.utterance_mut() else {
+            return;
+        };
+        let text = &mut utterance.text;
+        for rule in &self.rules {
+            let replaced = if rule.expand {
+                rule.pattern.replace_all(text, rule.replacement.as_str())
+            } else {
+                rule.pattern.replace_all(text, NoExpand(&rule.replacement))
+            };
+            if let Cow::Owned(replaced) = replaced {
+                *text = replaced;
+            }
+        }
+    }
+}
+
+/// Escape literal text and anchor it to word boundaries, so that a rule for
+/// `Ian` does not rewrite `Christian`
+pub(super) fn bounded(literal: &str) -> String {
+    let is_word = |c: Option<char>| c.is_some_and(char::is_alphanumeric);
+    format!(
+        "{}{}{}",
+        if is_word(literal.chars().next()) {
+            r"\b"
+        } else {
+            ""
+        },
+        regex::escape(literal),
+        if is_word(literal.chars().last()) {
+            r"\b"
+        } else {
+            ""
+        },
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn apply(rules: &Replacements, text: &str) -> String {
+        let mut line = Line::Recognised(text.into());
+        rules.apply(&mut line);
+        let Line::Recognised(utterance) = line else {
+            unreachable!()
+        };
+        utterance.text
+    }
+
+    #[test]
+    fn test_rules() {
+        let rules = Replacements::parse(
+            r#"
+            [words]
+            Ian = "Iain"
+            "St." = "Saint"
+
+            [phrases]
+            "holy spirit" = "Holy Spirit"
+            "cost $5" = "cost five"
+
+            [regex]
+            '\bpsalm (\d+)' = "Psalm $1"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            apply(&rules, "Ian is a Christian. ian"),
+            "Iain is a Christian. ian"
+        );
+        assert_eq!(apply(&rules, "St. Paul"), "Saint Paul");
+        assert_eq!(
+            apply(&rules, "The HOLY spirit, it cost $5"),
+            "The Holy Spirit, it cost five"
+        );
+        assert_eq!(
+            apply(&rules, "psalm 23 and psalm 1"),
+            "Psalm 23 and Psalm 1"
+        );
+    }
+
+    #[test]
+    fn test_applies_to_partial_lines() {
+        let rules = Replacements::parse("[words]\nJhon = \"John\"").unwrap();
+        let mut line = Line::Recognising("Jhon".into());
+        rules.apply(&mut line);
+        assert_eq!(line, Line::Recognising("John".into()));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(Replacements::parse("[regex]\n'(' = \"\"").is_err());
+        assert!(Replacements::parse("[unknown]\na = \"b\"").is_err());
+    }
+}
@@ -0,0 +1,215 @@
+//! A stand-in for the Azure Speech service, which follows a script for each
+//! connection so that the real client can be tested against it
+
+use azure_speech::{
+    extract_headers_and_data_from_binary_message,
+    extract_headers_and_data_from_text_message, make_text_payload,
+};
+use futures_util::SinkExt;
+use std::sync::{Arc, Mutex};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tokio_stream::StreamExt;
+use tokio_websockets::{CloseCode, Message, ServerBuilder, WebSocketStream};
+
+/// What to do with a connection from the recogniser
+pub enum Script {
+    /// Refuse the websocket handshake with an HTTP status, e.g.
+    /// `"401 Unauthorized"`
+    Reject(&'static str),
+    /// Answer the start of recognition with these messages, given as path
+    /// and JSON body, then wait for the client to disconnect
+    Accept(Vec<(&'static str, String)>),
+    /// Send the messages, then close the connection
+    AcceptThenClose(Vec<(&'static str, String)>),
+    /// Close the connection once recognition starts, giving this reason
+    Close(&'static str),
+    /// Answer each turn with its messages, ending all but the last once some
+    /// of its audio has arrived, then wait for the client to disconnect
+    Turns(Vec<Vec<(&'static str, String)>>),
+}
+
+/// The websocket handshake of a connection which was accepted, and how the
+/// recogniser set up a turn of recognition on it
+#[derive(Debug)]
+pub struct Request {
+    pub uri: String,
+    pub key: Option<String>,
+    /// Body of the turn's `speech.context` message
+    pub context: serde_json::Value,
+    /// Bytes of audio sent in earlier turns on the connection, not counting
+    /// headers
+    pub audio: usize,
+}
+
+pub struct SpeechService {
+    /// Address to give as `speech_endpoint`
+    pub url: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl SpeechService {
+    /// Listen on a free local port, following one script per connection
+    pub async fn start(scripts: impl IntoIterator<Item = Script>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let requests = Arc::<Mutex<Vec<Request>>>::default();
+        let scripts = scripts.into_iter().collect::<Vec<_>>();
+        tokio::spawn({
+            let requests = Arc::clone(&requests);
+            async move {
+                for script in scripts {
+                    let Ok((stream, _)) = listener.accept().await else {
+                        return;
+                    };
+                    tokio::spawn(serve(stream, script, Arc::clone(&requests)));
+                }
+            }
+        });
+        Self { url, requests }
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        std::mem::take(&mut self.requests.lock().unwrap())
+    }
+}
+
+async fn serve(
+    mut stream: TcpStream,
+    script: Script,
+    requests: Arc<Mutex<Vec<Request>>>,
+) {
+    let (turns, close) = match script {
+        Script::Reject(status) => {
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                match stream.read(&mut buf).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(n) => request.extend_from_slice(&buf[..n]),
+                }
+            }
+            let response =
+                format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\n\r\n");
+            let _ = stream.write_all(response.as_bytes()).await;
+            return;
+        }
+        Script::Accept(replies) => (vec![replies], None),
+        Script::AcceptThenClose(replies) => (vec![replies], Some("")),
+        Script::Close(reason) => (vec![Vec::new()], Some(reason)),
+        Script::Turns(turns) => (turns, None),
+    };
+
+    let (request, mut ws) = ServerBuilder::new().accept(stream).await.unwrap();
+    let uri = request.uri().to_string();
+    let key = request
+        .headers()
+        .get("Ocp-Apim-Subscription-Key")
+        .and_then(|key| key.to_str().ok())
+        .map(String::from);
+
+    let mut audio = 0;
+    let last = turns.len().saturating_sub(1);
+    for (index, replies) in turns.into_iter().enumerate() {
+        // Replies only reach the recogniser if they carry the request ID of
+        // the turn, which its `speech.context` message starts
+        let (request_id, context) = loop {
+            let Some(Ok(message)) = ws.next().await else {
+                return;
+            };
+            let Some(text) = message.as_text() else {
+                audio += audio_len(&message);
+                continue;
+            };
+            let (headers, body) =
+                extract_headers_and_data_from_text_message(text).unwrap();
+            if header(&headers, "Path").as_deref() == Some("speech.context") {
+                let context = serde_json::from_str(&body.unwrap()).unwrap();
+                break (header(&headers, "X-RequestId").unwrap(), context);
+            }
+        };
+        requests.lock().unwrap().push(Request {
+            uri: uri.clone(),
+            key: key.clone(),
+            context,
+            audio,
+        });
+
+        let replies =
+            std::iter::once(("turn.start", "{}".to_string())).chain(replies);
+        for (path, body) in replies {
+            if !reply(&mut ws, &request_id, path, &body).await {
+                return;
+            }
+        }
+
+        if index < last {
+            // End the turn part way through the audio
+            loop {
+                let Some(Ok(message)) = ws.next().await else {
+                    return;
+                };
+                let len = audio_len(&message);
+                audio += len;
+                if len > 0 {
+                    break;
+                }
+            }
+            if !reply(&mut ws, &request_id, "turn.end", "{}").await {
+                return;
+            }
+        }
+    }
+
+    if let Some(reason) = close {
+        let message = Message::close(Some(CloseCode::NORMAL_CLOSURE), reason);
+        let _ = ws.send(message).await;
+        let _ = ws.close().await;
+    } else {
+        // Take the audio until the client goes away
+        while let Some(Ok(_)) = ws.next().await {}
+    }
+}
+
+fn header(headers: &[(String, String)], name: &str) -> Option<String> {
+    headers
+        .iter()
+        .find(|(header, _)| header == name)
+        .map(|(_, value)| value.clone())
+}
+
+/// Bytes of audio in a message from the recogniser, leaving out the header
+/// which starts each turn along with its format
+fn audio_len(message: &Message) -> usize {
+    if !message.is_binary() {
+        return 0;
+    }
+    let (headers, data) =
+        extract_headers_and_data_from_binary_message(message.as_payload())
+            .unwrap();
+    if header(&headers, "Content-Type").is_some() {
+        return 0;
+    }
+    data.map_or(0, |data| data.len())
+}
+
+/// Send a message for the turn, returning whether the client is still there
+async fn reply(
+    ws: &mut WebSocketStream<TcpStream>,
+    request_id: &str,
+    path: &str,
+    body: &str,
+) -> bool {
+    let headers = vec![
+        ("X-RequestId".to_string(), request_id.to_string()),
+        ("Path".to_string(), path.to_string()),
+        (
+            "Content-Type".to_string(),
+            "application/json; charset=utf-8".to_string(),
+        ),
+    ];
+    let payload = make_text_payload(headers, Some(body));
+    ws.send(Message::text(payload)).await.is_ok()
+}
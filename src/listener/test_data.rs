@@ -0,0 +1,168 @@
+//! Caption lines replayed by the Test mode, either the built-in
+//! `test-data.txt` or a file from the test data directory.
+//!
+//! Files ending in `.jsonl` are replayed at their recorded cadence. Each line
+//! is an entry giving the time in seconds from the start of the replay and
+//! the caption line to show then:
+//!
+//! ```text
+//! {"offset": 0.8, "line": {"Recognising": {"text": "another"}}}
+//! {"offset": 2.1, "line": {"Recognised": {"text": "Another day."}}}
+//! ```
+//!
+//! Any other file uses the `RECOGNIZED: ` prefixed format of
+//! `test-data.txt`, with the lines spaced evenly.
+
+use crate::{Line, Result};
+use color_eyre::eyre::{WrapErr, eyre};
+use serde::{Deserialize, Serialize};
+use std::{path::Path, str::FromStr, time::Duration};
+
+const TEST_LINES: &str = include_str!("../test-data.txt");
+/// Spacing of lines without timestamps
+const LINE_DELAY: Duration = Duration::from_millis(300);
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Entry {
+    /// Seconds from the start of the replay
+    pub offset: f64,
+    /// When the line was heard, for recorded sessions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time: Option<String>,
+    pub line: Line,
+}
+
+#[derive(Debug, Default)]
+pub struct TestData {
+    entries: Vec<(Duration, Line)>,
+}
+
+impl TestData {
+    pub fn builtin() -> Result<Self> {
+        Self::parse_plain(TEST_LINES)
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        let test_data = if path
+            .extension()
+            .is_some_and(|extension| extension == "jsonl")
+        {
+            Self::parse_timed(&content)
+        } else {
+            Self::parse_plain(&content)
+        };
+        test_data.wrap_err_with(|| {
+            format!("Invalid test data in {}", path.display())
+        })
+    }
+
+    fn parse_plain(content: &str) -> Result<Self> {
+        let mut entries = Vec::new();
+        for (number, line) in numbered_lines(content) {
+            let line = Line::from_str(line)
+                .wrap_err_with(|| format!("Line {number}"))?;
+            entries.push((LINE_DELAY * entries.len() as u32, line));
+        }
+        Ok(Self { entries })
+    }
+
+    fn parse_timed(content: &str) -> Result<Self> {
+        let mut entries = Vec::new();
+        for (number, line) in numbered_lines(content) {
+            let entry = serde_json::from_str::<Entry>(line)
+                .wrap_err_with(|| format!("Line {number}"))?;
+            let offset = Duration::try_from_secs_f64(entry.offset)
+                .wrap_err_with(|| format!("Line {number}: invalid offset"))?;
+            if let Some((previous, _)) = entries.last()
+                && offset < *previous
+            {
+                return Err(eyre!("Line {number}: offset goes backwards"));
+            }
+            entries.push((offset, entry.line));
+        }
+        Ok(Self { entries })
+    }
+
+    pub fn entries(&self) -> &[(Duration, Line)] {
+        &self.entries
+    }
+
+    /// Time from the start of one pass to the start of the next when
+    /// looping
+    pub fn period(&self) -> Duration {
+        self.entries
+            .last()
+            .map_or(Duration::ZERO, |(offset, _)| *offset + LINE_DELAY)
+    }
+}
+
+/// Non-empty lines with their line numbers, counting from 1
+fn numbered_lines(content: &str) -> impl Iterator<Item = (usize, &str)> {
+    content
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_builtin() {
+        let test_data = TestData::builtin().unwrap();
+        assert!(!test_data.entries().is_empty());
+        assert_eq!(test_data.entries()[2].0, LINE_DELAY * 2);
+    }
+
+    #[test]
+    fn test_parse_timed() {
+        let test_data = TestData::parse_timed(
+            r#"{"offset": 0.5, "line": {"Recognising": {"text": "another"}}}
+
+{"offset": 2, "line": {"Recognised": {"text": "Another day."}}}
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            test_data.entries(),
+            [
+                (
+                    Duration::from_millis(500),
+                    Line::Recognising("another".into())
+                ),
+                (
+                    Duration::from_secs(2),
+                    Line::Recognised("Another day.".into())
+                ),
+            ]
+        );
+        assert_eq!(test_data.period(), Duration::from_millis(2300));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let err = TestData::parse_timed(
+            r#"{"offset": 1, "line": {"Recognised": {"text": "one"}}}
+{"offset": 2, "line": {"Recognised": {"text": 2}}}
+"#,
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "Line 2");
+
+        let err = TestData::parse_timed(
+            r#"{"offset": 2, "line": {"Recognised": {"text": "two"}}}
+{"offset": 1, "line": {"Recognised": {"text": "one"}}}
+"#,
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "Line 2: offset goes backwards");
+
+        let err = TestData::parse_plain("RECOGNIZED: fine\nnot a line\n")
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Line 2");
+    }
+}
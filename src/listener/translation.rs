@@ -0,0 +1,119 @@
+//! Translation of finished lines into a second language, shown alongside the
+//! original captions
+
+use crate::{Result, config::TranslatorConfig};
+use color_eyre::eyre::{WrapErr, eyre};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// How long to wait for a translation before the line is left without one
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A service which translates text into a target language
+pub trait Translator: Send + Sync + 'static {
+    /// Translate `text`, detecting its source language, into `to`
+    fn translate(
+        &self,
+        text: &str,
+        to: &str,
+    ) -> impl Future<Output = Result<String>> + Send;
+}
+
+/// Translate a line, giving up if the service is slow to reply
+pub async fn translate<T: Translator>(
+    translator: &T,
+    text: &str,
+    to: &str,
+) -> Option<String> {
+    match tokio::time::timeout(TIMEOUT, translator.translate(text, to)).await {
+        Ok(Ok(translation)) => Some(translation),
+        Ok(Err(err)) => {
+            warn!("Translation failed: {err:?}");
+            None
+        }
+        Err(_) => {
+            warn!("Translation timed out");
+            None
+        }
+    }
+}
+
+/// Azure AI Translator, using the v3 text translation API
+pub struct AzureTranslator {
+    client: reqwest::Client,
+    config: TranslatorConfig,
+}
+
+impl AzureTranslator {
+    pub fn new(config: TranslatorConfig) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            config,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Request<'a> {
+    #[serde(rename = "Text")]
+    text: &'a str,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    translations: Vec<Translation>,
+}
+
+#[derive(Deserialize)]
+struct Translation {
+    text: String,
+}
+
+impl Translator for AzureTranslator {
+    async fn translate(&self, text: &str, to: &str) -> Result<String> {
+        let body = self
+            .client
+            .post(format!("{}/translate", self.config.endpoint))
+            .query(&[("api-version", "3.0"), ("to", to)])
+            .header("Ocp-Apim-Subscription-Key", &self.config.key)
+            .header("Ocp-Apim-Subscription-Region", &self.config.region)
+            .json(&[Request { text }])
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        parse_response(&body)
+    }
+}
+
+fn parse_response(body: &str) -> Result<String> {
+    let response: Vec<Response> = serde_json::from_str(body)
+        .wrap_err_with(|| format!("Invalid translation response: {body}"))?;
+    response
+        .into_iter()
+        .next()
+        .and_then(|response| response.translations.into_iter().next())
+        .map(|translation| translation.text)
+        .ok_or_else(|| eyre!("No translation in response: {body}"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse_response() {
+        assert_eq!(
+            parse_response(
+                r#"[{"detectedLanguage":{"language":"en","score":1.0},
+                "translations":[{"text":"こんにちは","to":"ja"}]}]"#
+            )
+            .unwrap(),
+            "こんにちは"
+        );
+        assert!(parse_response("[]").is_err());
+        assert!(parse_response(r#"{"error":{"code":401000}}"#).is_err());
+    }
+}
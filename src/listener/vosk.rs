@@ -0,0 +1,150 @@
+use super::{
+    RecognitionBackend, SetupState,
+    audio::{self, AudioEncoding},
+};
+use crate::{Line, Result, Utterance, Word, config::Config};
+use color_eyre::eyre::eyre;
+use std::{sync::Arc, time::Duration};
+use tokio::{runtime::Handle, sync::mpsc};
+use tokio_stream::{StreamExt, wrappers::ReceiverStream};
+use vosk::{CompleteResultSingle, DecodingState, Model, Recognizer};
+
+const SAMPLE_RATE: f32 = 16_000.0;
+
+pub(super) struct VoskBackend {
+    model: Arc<Model>,
+}
+
+impl VoskBackend {
+    pub fn new(config: &Config) -> Result<Self> {
+        let Some(path) = &config.vosk_model else {
+            return Err(eyre!("vosk_model is required for the vosk listener"));
+        };
+        info!("Loading vosk model from {}", path.display());
+        let model = Model::new(path.to_string_lossy()).ok_or_else(|| {
+            eyre!("Failed to load vosk model from {}", path.display())
+        })?;
+        Ok(Self {
+            model: Arc::new(model),
+        })
+    }
+}
+
+impl RecognitionBackend for VoskBackend {
+    type Session = ReceiverStream<Result<Line>>;
+
+    async fn connect(
+        &self,
+        setup_state: &SetupState,
+        config: &Config,
+    ) -> Result<Self::Session> {
+        if setup_state.wordlist.is_some() {
+            warn!("Wordlists are not supported by the vosk backend");
+        }
+        if setup_state.auto_detect {
+            warn!(
+                "Language auto-detection is not supported by the vosk backend"
+            );
+        }
+
+        let mut recognizer = Recognizer::new(&self.model, SAMPLE_RATE)
+            .ok_or_else(|| eyre!("Failed to create vosk recognizer"))?;
+        recognizer.set_words(true);
+        let mut audio = audio::capture(
+            &config.audio,
+            setup_state,
+            AudioEncoding::Pcm16kMono,
+        )
+        .await?;
+        let (tx, rx) = mpsc::channel(10);
+
+        // The recognizer is CPU-bound so it runs on the blocking pool and
+        // pulls audio from the async capture
+        let handle = Handle::current();
+        tokio::task::spawn_blocking(move || {
+            let mut partial = String::new();
+            while let Some(chunk) = handle.block_on(audio.next()) {
+                // Most chunks give no line, so check for the session having
+                // been dropped rather than waiting for a send to fail
+                if tx.is_closed() {
+                    break;
+                }
+                let samples = chunk
+                    .chunks_exact(2)
+                    .map(|pair| i16::from_le_bytes([pair[0], pair[1]]))
+                    .collect::<Vec<_>>();
+
+                let line = match recognizer.accept_waveform(&samples) {
+                    Ok(DecodingState::Finalized) => {
+                        partial.clear();
+                        recognizer
+                            .result()
+                            .single()
+                            .map(utterance)
+                            .filter(|utterance| !utterance.text.is_empty())
+                            .map(|utterance| Ok(Line::Recognised(utterance)))
+                    }
+                    Ok(DecodingState::Running) => {
+                        let text = recognizer.partial_result().partial.trim();
+                        if text.is_empty() || text == partial {
+                            None
+                        } else {
+                            partial = text.to_string();
+                            Some(Ok(Line::Recognising(partial.clone().into())))
+                        }
+                    }
+                    Ok(DecodingState::Failed) => {
+                        Some(Err(eyre!("vosk failed to decode audio")))
+                    }
+                    Err(err) => Some(Err(eyre!("{err:?}"))),
+                };
+
+                if let Some(line) = line
+                    && tx.blocking_send(line).is_err()
+                {
+                    break;
+                }
+            }
+            info!("vosk recognizer stopped");
+        });
+
+        Ok(ReceiverStream::new(rx))
+    }
+
+    async fn disconnect(&self, session: Self::Session) {
+        // Dropping the receiver stops the recognizer thread at its next chunk
+        // of audio, which in turn drops the audio stream
+        drop(session);
+    }
+}
+
+/// Convert a final result, with vosk's word times given in seconds from the
+/// start of the session
+fn utterance(result: CompleteResultSingle) -> Utterance {
+    let words = result
+        .result
+        .iter()
+        .map(|word| Word {
+            text: word.word.to_string(),
+            offset: Some(Duration::from_secs_f32(word.start)),
+            duration: Some(Duration::from_secs_f32(word.end - word.start)),
+            confidence: Some(word.conf),
+        })
+        .collect::<Vec<_>>();
+    let confidence = (!words.is_empty()).then(|| {
+        result.result.iter().map(|word| word.conf).sum::<f32>()
+            / result.result.len() as f32
+    });
+    let span = result.result.first().zip(result.result.last());
+
+    Utterance {
+        text: result.text.trim().to_string(),
+        offset: span.map(|(first, _)| Duration::from_secs_f32(first.start)),
+        duration: span.map(|(first, last)| {
+            Duration::from_secs_f32(last.end - first.start)
+        }),
+        confidence,
+        words,
+        ..Default::default()
+    }
+}
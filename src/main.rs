@@ -7,41 +7,122 @@ use eframe::epaint::text::{FontInsert, InsertFontFamily};
 use egui::{FontFamily, FontId, TextStyle, ViewportBuilder};
 use serde::{Deserialize, Serialize};
 use std::{
-    path::Path,
+    collections::BTreeMap,
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
     str::FromStr,
-    sync::{Arc, atomic::AtomicBool},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
 };
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
 
 #[macro_use]
 extern crate tracing;
 
 mod config;
+#[cfg(target_os = "linux")]
+mod gpio;
 mod gui;
+mod keybindings;
 mod listener;
+mod remote;
+mod transcript;
 mod xrandr;
 
 const NOTO_SANS: &[u8] = include_bytes!("../fonts/NotoSans-Regular.ttf");
 
 const LINE_BUFFER_SIZE: usize = 30;
 
+/// Room for the burst of messages which restores the saved controls at
+/// startup, with plenty to spare for the operator's own changes
+const CONTROL_CHANNEL_SIZE: usize = 32;
+
 const MIN_FONT: f32 = 30.0;
 const MAX_FONT: f32 = 400.0;
 const MIN_SUBTITLE_HEIGHT: f32 = 0.1;
 const MAX_SUBTITLE_HEIGHT: f32 = 0.9;
+const MAX_REPLAY_SPEED: f32 = 8.0;
 
 const PREFIX_RECOGNISING: &str = "RECOGNIZING: ";
 const PREFIX_RECOGNISED: &str = "RECOGNIZED: ";
 // https://learn.microsoft.com/en-us/azure/ai-services/speech-service/language-support?tabs=stt
+// Used when `languages` is not set in the config
 const LANGUAGE_OPTIONS: &[&str] = &["en-GB", "en-IE", "en-US", "ja-JP"];
 
-#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 enum Line {
-    Recognising(String),
-    Recognised(String),
+    Recognising(Utterance),
+    Recognised(Utterance),
+    /// Translation of the finished line with the given id, which follows the
+    /// line once the translator replies
+    Translated {
+        id: u64,
+        text: String,
+    },
+}
+
+/// Recognised text along with where it falls in the audio stream and how
+/// sure the recogniser is of it, as far as the backend reports them
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+struct Utterance {
+    text: String,
+    /// Numbers finished lines so that later updates can refer back to them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<u64>,
+    /// Start of the utterance relative to the start of the connection
+    offset: Option<Duration>,
+    duration: Option<Duration>,
+    /// From 0 to 1
+    confidence: Option<f32>,
+    /// Individual words, for finished lines
+    words: Vec<Word>,
+    /// Less likely readings of the audio, most likely first
+    alternatives: Vec<Alternative>,
+    /// Locale identified by the recogniser when auto-detecting
+    language: Option<Arc<str>>,
+    /// Identifier of the speaker, from backends which can tell speakers apart
+    speaker: Option<Arc<str>>,
+    /// Text in the chosen translation language, for finished lines
+    translation: Option<String>,
+}
+
+/// A single word of an utterance, as spoken rather than as displayed
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+struct Word {
+    text: String,
+    offset: Option<Duration>,
+    duration: Option<Duration>,
+    confidence: Option<f32>,
 }
 
-#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize)]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+struct Alternative {
+    text: String,
+    confidence: Option<f32>,
+}
+
+impl From<String> for Utterance {
+    fn from(text: String) -> Self {
+        Self {
+            text,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<&str> for Utterance {
+    fn from(text: &str) -> Self {
+        String::from(text).into()
+    }
+}
+
+#[derive(
+    Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize,
+)]
 enum RunState {
     #[default]
     Stopped,
@@ -50,11 +131,76 @@ enum RunState {
     HoldingSlide,
 }
 
+/// Operations which can be bound to keys or physical buttons
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+enum Action {
+    ShowControls,
+    HideControls,
+    FontSmaller,
+    FontLarger,
+    SubtitleTaller,
+    SubtitleShorter,
+    ToggleDarkMode,
+    SwapDisplayMode,
+    ToggleRunning,
+    ToggleTestMode,
+    ToggleHoldingSlide,
+    ToggleFullscreen,
+    Stop,
+    Clear,
+}
+
+impl Action {
+    /// Needs the egui context, so can only come from the keyboard
+    const fn needs_window(self) -> bool {
+        matches!(self, Self::ToggleFullscreen)
+    }
+}
+
 #[derive(Debug)]
 enum ControlMessage {
     SetState(RunState),
     SetWordlist(Option<Arc<str>>),
     GetWordlist(oneshot::Sender<Wordlist>),
+    SetLanguage(Arc<str>),
+    /// Identify the language continuously instead of using the chosen one
+    SetAutoDetect(bool),
+    SetTranslation(Option<Arc<str>>),
+    SetProfanity(ProfanityMode),
+    SetAudioDevice(Option<Arc<str>>),
+    SetReplay(Option<Replay>),
+    /// File from the test data directory, or `None` for the built-in lines
+    SetTestData(Option<Arc<str>>),
+    SetTestLoop(bool),
+    /// The operator corrected a finished line before it was shown, or with
+    /// `None` dropped it
+    CorrectLine {
+        id: u64,
+        text: Option<String>,
+    },
+}
+
+/// Recorded audio to recognise in place of the live input
+#[derive(Clone, Debug, PartialEq)]
+struct Replay {
+    /// File in the recordings directory, or the URL of a stream
+    source: Arc<str>,
+    /// Playback speed, where 1 is real time
+    speed: f32,
+}
+
+impl Line {
+    const fn utterance_mut(&mut self) -> Option<&mut Utterance> {
+        match self {
+            Self::Recognising(utterance) | Self::Recognised(utterance) => {
+                Some(utterance)
+            }
+            Self::Translated { .. } => None,
+        }
+    }
 }
 
 impl FromStr for Line {
@@ -85,14 +231,19 @@ async fn main() -> Result<()> {
     let config = config::Config::load(args.config)?;
 
     let (tx, rx) = mpsc::channel(10);
-    let (control_tx, control_rx) = mpsc::channel(5);
+    let (control_tx, control_rx) = mpsc::channel(CONTROL_CHANNEL_SIZE);
+    let (captions_tx, _) = broadcast::channel(LINE_BUFFER_SIZE);
+    let (levels_tx, levels_rx) = watch::channel(None);
+    let (status_tx, status_rx) = watch::channel(listener::Status::default());
 
     info!("Starting captioninator");
-    let auth = match (config.region.clone(), config.key.clone()) {
-        (Some(region), Some(key)) => listener::Auth { region, key },
-        _ => Err(eyre!("Region and key are required for Azure listener"))?,
-    };
-    listener::start(tx.clone(), control_rx, auth, config.clone());
+    listener::start(
+        tx.clone(),
+        control_rx,
+        levels_tx,
+        status_tx,
+        config.clone(),
+    )?;
 
     // Use set position to position the window on the secondary display.
     // The position is derived from a call to xrandr
@@ -104,8 +255,35 @@ async fn main() -> Result<()> {
         ..Default::default()
     };
 
-    let mut app =
-        gui::MyApp::new(rx, config, control_tx, monitor_positions).await?;
+    let mut app = gui::MyApp::new(
+        rx,
+        captions_tx.clone(),
+        config.clone(),
+        control_tx,
+        levels_rx,
+        status_rx,
+        monitor_positions,
+    )
+    .await?;
+
+    if let Some(port) = config.http_port {
+        let address = config.http_address.unwrap_or(Ipv4Addr::LOCALHOST.into());
+        remote::start(
+            SocketAddr::new(address, port),
+            app.control_state(),
+            captions_tx,
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    if let Some(gpio_config) = config.gpio.clone() {
+        let control_state = app.control_state();
+        if let Err(err) = gpio::start(gpio_config, move |action| {
+            control_state.lock().unwrap().apply(action);
+        }) {
+            error!("Unable to start GPIO buttons: {err:?}");
+        }
+    }
 
     eframe::run_native(
         "captioninator",
@@ -226,10 +404,50 @@ struct ControlState {
     run_state: RunState,
     wordlist_options: Vec<Arc<str>>,
     wordlist: Option<Arc<str>>,
+    language_options: Vec<Arc<str>>,
+    language: Arc<str>,
+    auto_detect_language: bool,
+    translation_options: Vec<Arc<str>>,
+    translation: Option<Arc<str>>,
+    translation_layout: TranslationLayout,
+    profanity: ProfanityMode,
+    audio_config: config::AudioConfig,
+    audio_device_options: Vec<Arc<str>>,
+    /// Capture device, or `None` to use the one in the config
+    audio_device: Option<Arc<str>>,
+    replay_options: Vec<Arc<str>>,
+    /// Recording or stream to recognise instead of the input
+    replay: Option<Arc<str>>,
+    replay_url: String,
+    replay_speed: f32,
+    test_data_dir: Option<PathBuf>,
+    test_data_options: Vec<Arc<str>>,
+    test_data: Option<Arc<str>>,
+    /// Start the test data again once it has all been shown
+    test_loop: bool,
+    /// Input level while capturing
+    audio_level: watch::Receiver<Option<listener::AudioLevel>>,
+    signal_monitor: gui::signal::Monitor,
+    /// What the listener reports it is doing
+    listener_status: watch::Receiver<listener::Status>,
+    /// Latest state for the remote control panels, which are sent it when it
+    /// changes
+    snapshots: watch::Sender<remote::Snapshot>,
+    /// Dim words the recogniser is less sure of than `confidence_threshold`
+    highlight_low_confidence: bool,
+    confidence_threshold: f32,
+    /// Operator-assigned names for speaker identifiers seen so far
+    speaker_names: BTreeMap<Arc<str>, String>,
+    /// Hold finished lines for the operator to check before showing them
+    moderation_enabled: bool,
+    /// Seconds before a held line is released automatically
+    moderation_delay: f32,
+    moderation_queue: gui::moderation::Queue,
     request_close: AtomicBool,
     request_clear: AtomicBool,
     image_options: Vec<Arc<str>>,
     selected_image: Option<Arc<str>>,
+    keybindings: keybindings::KeyBindings,
 }
 
 impl ControlState {
@@ -247,11 +465,16 @@ impl ControlState {
         }
     }
 
-    fn toggle_running(&mut self) {
-        self.run_state = match self.run_state {
-            RunState::Running | RunState::Test => RunState::Stopped,
-            RunState::Stopped | RunState::HoldingSlide => RunState::Running,
-        };
+    /// Number of finished lines kept on screen
+    const fn line_limit(&self) -> usize {
+        match self.display_mode {
+            DisplayMode::Fullscreen => LINE_BUFFER_SIZE,
+            DisplayMode::Subtitle => 4,
+        }
+    }
+
+    fn set_run_state(&mut self, run_state: RunState) {
+        self.run_state = run_state;
         if let Err(err) = self
             .control_tx
             .try_send(ControlMessage::SetState(self.run_state))
@@ -260,51 +483,163 @@ impl ControlState {
         }
     }
 
+    /// Follow the listener when it changes state by itself, e.g. dropping
+    /// back to Stopped after a failed session
+    fn sync_listener_status(&mut self) {
+        if self.listener_status.has_changed().unwrap_or(false) {
+            self.run_state = self.listener_status.borrow_and_update().run_state;
+        }
+    }
+
+    fn toggle_running(&mut self) {
+        self.set_run_state(match self.run_state {
+            RunState::Running | RunState::Test => RunState::Stopped,
+            RunState::Stopped | RunState::HoldingSlide => RunState::Running,
+        });
+    }
+
     fn toggle_test_mode(&mut self) {
-        self.run_state = match self.run_state {
+        self.set_run_state(match self.run_state {
             RunState::Running | RunState::Test => RunState::Stopped,
             RunState::Stopped | RunState::HoldingSlide => RunState::Test,
-        };
-        if let Err(err) = self
-            .control_tx
-            .try_send(ControlMessage::SetState(self.run_state))
-        {
-            error!("{err}");
-        }
+        });
     }
 
     fn toggle_holding_slide(&mut self) {
-        self.run_state = match self.run_state {
+        self.set_run_state(match self.run_state {
             RunState::HoldingSlide => RunState::Stopped,
             RunState::Running | RunState::Stopped | RunState::Test => {
                 RunState::HoldingSlide
             }
-        };
-        dbg!(self.run_state);
-        dbg!(&self.selected_image);
+        });
+    }
+
+    fn stop(&mut self) {
+        self.set_run_state(RunState::Stopped);
+    }
+
+    fn apply(&mut self, action: Action) {
+        match action {
+            Action::ShowControls => self.state = gui::State::Config,
+            Action::HideControls => self.state = gui::State::Normal,
+            Action::FontSmaller => *self.font_size_mut() -= 1.0,
+            Action::FontLarger => *self.font_size_mut() += 1.0,
+            Action::SubtitleTaller => self.subtitle_height_proportion += 0.1,
+            Action::SubtitleShorter => self.subtitle_height_proportion -= 0.1,
+            Action::ToggleDarkMode => {
+                self.dark_mode_requested = !self.dark_mode_enabled;
+            }
+            Action::SwapDisplayMode => self.display_mode.swap(),
+            Action::ToggleRunning => self.toggle_running(),
+            Action::ToggleTestMode => self.toggle_test_mode(),
+            Action::ToggleHoldingSlide => self.toggle_holding_slide(),
+            // Needs the egui context so is handled by gui::input
+            Action::ToggleFullscreen => {}
+            Action::Stop => self.stop(),
+            Action::Clear => self.request_clear.store(true, Ordering::Relaxed),
+        }
+
+        *self.font_size_mut() = self.font_size().clamp(MIN_FONT, MAX_FONT);
+        self.subtitle_height_proportion = self
+            .subtitle_height_proportion
+            .clamp(MIN_SUBTITLE_HEIGHT, MAX_SUBTITLE_HEIGHT);
+    }
+
+    /// Shortcut hint to append to a control label, e.g. ` [Space]`
+    fn key_hint(&self, action: Action) -> String {
+        self.keybindings
+            .label(action)
+            .map(|label| format!(" [{label}]"))
+            .unwrap_or_default()
+    }
+
+    /// Name to show for a speaker, falling back to their identifier
+    fn speaker_name<'a>(&'a self, speaker: &'a str) -> &'a str {
+        self.speaker_names
+            .get(speaker)
+            .map(String::as_str)
+            .filter(|name| !name.is_empty())
+            .unwrap_or(speaker)
+    }
+
+    fn update_wordlist(&mut self) {
         if let Err(err) = self
             .control_tx
-            .try_send(ControlMessage::SetState(self.run_state))
+            .try_send(ControlMessage::SetWordlist(self.wordlist.clone()))
         {
             error!("{err}");
         }
     }
 
-    fn stop(&mut self) {
-        self.run_state = RunState::Stopped;
+    fn update_language(&mut self) {
+        if let Err(err) = self
+            .control_tx
+            .try_send(ControlMessage::SetLanguage(self.language.clone()))
+        {
+            error!("{err}");
+        }
+        if let Err(err) = self
+            .control_tx
+            .try_send(ControlMessage::SetAutoDetect(self.auto_detect_language))
+        {
+            error!("{err}");
+        }
+    }
 
+    fn update_audio_device(&mut self) {
         if let Err(err) = self
             .control_tx
-            .try_send(ControlMessage::SetState(self.run_state))
+            .try_send(ControlMessage::SetAudioDevice(self.audio_device.clone()))
         {
             error!("{err}");
         }
     }
 
-    fn update_wordlist(&mut self) {
+    fn update_replay(&mut self) {
+        let replay = self.replay.clone().map(|source| Replay {
+            source,
+            speed: self.replay_speed,
+        });
+        if let Err(err) =
+            self.control_tx.try_send(ControlMessage::SetReplay(replay))
+        {
+            error!("{err}");
+        }
+    }
+
+    /// Look again for files in the directories, e.g. for sessions recorded
+    /// since starting
+    fn refresh_replays(&mut self) {
+        (self.test_data_options, self.replay_options) = list_replays(
+            self.test_data_dir.as_deref(),
+            self.audio_config.recordings_dir.as_deref(),
+        );
+    }
+
+    fn update_test_data(&mut self) {
+        for msg in [
+            ControlMessage::SetTestData(self.test_data.clone()),
+            ControlMessage::SetTestLoop(self.test_loop),
+        ] {
+            if let Err(err) = self.control_tx.try_send(msg) {
+                error!("{err}");
+            }
+        }
+    }
+
+    fn update_profanity(&mut self) {
         if let Err(err) = self
             .control_tx
-            .try_send(ControlMessage::SetWordlist(self.wordlist.clone()))
+            .try_send(ControlMessage::SetProfanity(self.profanity))
+        {
+            error!("{err}");
+        }
+    }
+
+    fn update_translation(&mut self) {
+        if let Err(err) = self
+            .control_tx
+            .try_send(ControlMessage::SetTranslation(self.translation.clone()))
         {
             error!("{err}");
         }
@@ -329,13 +664,45 @@ impl DisplayMode {
     }
 }
 
+/// What to do with profanity in recognised text
+#[derive(
+    Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize,
+)]
+enum ProfanityMode {
+    /// Show it as recognised
+    #[default]
+    Raw,
+    /// Replace each letter with an asterisk
+    Masked,
+    /// Leave it out entirely
+    Removed,
+}
+
+/// How translations are placed relative to the original text
+#[derive(
+    Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize,
+)]
+enum TranslationLayout {
+    /// Each translation on the line below its original
+    #[default]
+    Paired,
+    /// Originals on the left and translations on the right
+    Columns,
+}
+
+/// Names of the visible files in `dir`, or none if it can't be read, e.g.
+/// because it hasn't been created yet
 fn list_directory(dir: &Path) -> Vec<Arc<str>> {
     let mut options = Vec::new();
 
-    for entry in dir
-        .read_dir()
-        .unwrap_or_else(|err| panic!("Path: {}\n{:?}", dir.display(), err))
-    {
+    let entries = match dir.read_dir() {
+        Ok(entries) => entries,
+        Err(err) => {
+            warn!("Unable to list {}: {err}", dir.display());
+            return options;
+        }
+    };
+    for entry in entries {
         let Ok(entry) = entry else { continue };
         let Ok(file_type) = entry.file_type() else {
             continue;
@@ -353,3 +720,70 @@ fn list_directory(dir: &Path) -> Vec<Arc<str>> {
 
     options
 }
+
+/// Files offered for the Test mode and for replay, in that order. Session
+/// logs saved next to their audio are offered for the Test mode.
+fn list_replays(
+    test_data_dir: Option<&Path>,
+    recordings_dir: Option<&Path>,
+) -> (Vec<Arc<str>>, Vec<Arc<str>>) {
+    let list = |dir: Option<&Path>| dir.map(list_directory).unwrap_or_default();
+    let (logs, recordings): (Vec<_>, _) = list(recordings_dir)
+        .into_iter()
+        .partition(|name| name.ends_with(".jsonl"));
+    let mut test_data = list(test_data_dir);
+    if test_data_dir != recordings_dir {
+        test_data.extend(logs);
+    }
+    (test_data, recordings)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_list_directory() {
+        let dir = std::env::temp_dir()
+            .join(format!("captioninator-list-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("subdirectory")).unwrap();
+        std::fs::write(dir.join("session.wav"), "").unwrap();
+        std::fs::write(dir.join(".hidden"), "").unwrap();
+
+        let options = list_directory(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(options, [Arc::from("session.wav")]);
+
+        // Gone, or not created yet
+        assert!(list_directory(&dir).is_empty());
+    }
+
+    #[test]
+    fn test_list_replays() {
+        let dir = std::env::temp_dir()
+            .join(format!("captioninator-replays-{}", std::process::id()));
+        let test_data_dir = dir.join("test-data");
+        let recordings_dir = dir.join("recordings");
+        std::fs::create_dir_all(&test_data_dir).unwrap();
+        std::fs::create_dir_all(&recordings_dir).unwrap();
+        std::fs::write(test_data_dir.join("glitch.txt"), "").unwrap();
+        std::fs::write(recordings_dir.join("session.webm"), "").unwrap();
+        std::fs::write(recordings_dir.join("session.jsonl"), "").unwrap();
+
+        let (mut test_data, replays) =
+            list_replays(Some(&test_data_dir), Some(&recordings_dir));
+        let (shared, _) =
+            list_replays(Some(&recordings_dir), Some(&recordings_dir));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // Session logs are replayed by the Test mode rather than recognised
+        test_data.sort();
+        assert_eq!(
+            test_data,
+            [Arc::from("glitch.txt"), Arc::from("session.jsonl")]
+        );
+        assert_eq!(replays, [Arc::from("session.webm")]);
+        assert_eq!(shared.len(), 2);
+    }
+}
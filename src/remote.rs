@@ -0,0 +1,653 @@
+//! HTTP/WebSocket API for controlling the captions remotely
+//!
+//! * `GET /` serves the operator control panel
+//! * `GET /overlay` serves a transparent caption overlay for use as an OBS
+//!   browser source
+//! * `GET /api/state` returns the current control state
+//! * `GET /api/ws` pushes the control state whenever it changes
+//! * `GET /api/captions` pushes each caption line as it is shown, and
+//!   `"Clear"` when the captions are cleared
+//! * `POST /api/{run,stop,test,holding_slide,clear}` perform the
+//!   corresponding operation
+//! * `PUT /api/{font_size,subtitle_height,dark_mode,display_mode,wordlist,
+//!   selected_image}` with a body of `{"value": ...}` change a setting
+
+use crate::{
+    Action, ControlState, DisplayMode, Line, MAX_FONT, MAX_SUBTITLE_HEIGHT,
+    MIN_FONT, MIN_SUBTITLE_HEIGHT, NOTO_SANS, RunState, TranslationLayout,
+};
+use axum::{
+    Json, Router,
+    extract::{
+        FromRef, State,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    http::{StatusCode, header},
+    response::{Html, IntoResponse, Response},
+    routing::{get, post, put},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::broadcast;
+
+const CONTROL_PAGE: &str = include_str!("../web/control.html");
+const OVERLAY_PAGE: &str = include_str!("../web/overlay.html");
+
+type Shared = Arc<Mutex<ControlState>>;
+
+/// Pushed to caption viewers, following what is shown on screen
+#[derive(Clone, Debug, Serialize)]
+pub(crate) enum Caption {
+    Line(Line),
+    Clear,
+}
+
+#[derive(Clone)]
+struct AppState {
+    control_state: Shared,
+    captions_tx: broadcast::Sender<Caption>,
+}
+
+impl FromRef<AppState> for Shared {
+    fn from_ref(state: &AppState) -> Self {
+        Arc::clone(&state.control_state)
+    }
+}
+
+impl FromRef<AppState> for broadcast::Sender<Caption> {
+    fn from_ref(state: &AppState) -> Self {
+        state.captions_tx.clone()
+    }
+}
+type ApiResult = Result<Json<Snapshot>, (StatusCode, String)>;
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub(crate) struct Snapshot {
+    run_state: RunState,
+    font_size: f32,
+    line_limit: usize,
+    subtitle_height_proportion: f32,
+    dark_mode: bool,
+    display_mode: DisplayMode,
+    wordlist_options: Vec<Arc<str>>,
+    wordlist: Option<Arc<str>>,
+    image_options: Vec<Arc<str>>,
+    selected_image: Option<Arc<str>>,
+    language_options: Vec<Arc<str>>,
+    /// Name to show for each speaker identifier seen so far
+    speaker_names: BTreeMap<Arc<str>, String>,
+    translation: Option<Arc<str>>,
+    translation_layout: TranslationLayout,
+    highlight_low_confidence: bool,
+    confidence_threshold: f32,
+}
+
+impl From<&ControlState> for Snapshot {
+    fn from(control_state: &ControlState) -> Self {
+        Self {
+            run_state: control_state.run_state,
+            font_size: control_state.font_size(),
+            line_limit: control_state.line_limit(),
+            subtitle_height_proportion: control_state
+                .subtitle_height_proportion,
+            dark_mode: control_state.dark_mode_requested,
+            display_mode: control_state.display_mode,
+            wordlist_options: control_state.wordlist_options.clone(),
+            wordlist: control_state.wordlist.clone(),
+            image_options: control_state.image_options.clone(),
+            selected_image: control_state.selected_image.clone(),
+            language_options: control_state.language_options.clone(),
+            speaker_names: control_state
+                .speaker_names
+                .keys()
+                .map(|id| {
+                    (id.clone(), control_state.speaker_name(id).to_string())
+                })
+                .collect(),
+            translation: control_state.translation.clone(),
+            translation_layout: control_state.translation_layout,
+            highlight_low_confidence: control_state.highlight_low_confidence,
+            confidence_threshold: control_state.confidence_threshold,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Value<T> {
+    value: T,
+}
+
+pub fn start(
+    addr: SocketAddr,
+    control_state: Shared,
+    captions_tx: broadcast::Sender<Caption>,
+) {
+    let router = router(control_state, captions_tx);
+
+    tokio::task::spawn(async move {
+        let listener = match tokio::net::TcpListener::bind(addr).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!("Unable to listen on {addr}: {err}");
+                return;
+            }
+        };
+        info!("Remote control listening on {addr}");
+        if let Err(err) = axum::serve(listener, router).await {
+            error!("Remote control server failed: {err}");
+        }
+    });
+}
+
+fn router(
+    control_state: Shared,
+    captions_tx: broadcast::Sender<Caption>,
+) -> Router {
+    Router::new()
+        .route("/", get(control_page))
+        .route("/overlay", get(|| async { Html(OVERLAY_PAGE) }))
+        .route("/fonts/NotoSans-Regular.ttf", get(font))
+        .route("/api/state", get(state))
+        .route("/api/ws", get(websocket))
+        .route("/api/captions", get(captions))
+        .route("/api/run", post(run))
+        .route("/api/stop", post(stop))
+        .route("/api/test", post(test))
+        .route("/api/holding_slide", post(holding_slide))
+        .route("/api/clear", post(clear))
+        .route("/api/font_size", put(font_size))
+        .route("/api/subtitle_height", put(subtitle_height))
+        .route("/api/dark_mode", put(dark_mode))
+        .route("/api/display_mode", put(display_mode))
+        .route("/api/wordlist", put(wordlist))
+        .route("/api/selected_image", put(selected_image))
+        .with_state(AppState {
+            control_state,
+            captions_tx,
+        })
+}
+
+fn update(
+    control_state: &Shared,
+    f: impl FnOnce(&mut ControlState) -> Result<(), String>,
+) -> ApiResult {
+    let mut control_state = control_state.lock().unwrap();
+    f(&mut control_state).map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+    publish(&control_state);
+    Ok(Json(Snapshot::from(&*control_state)))
+}
+
+/// Pass the control state on to the WebSocket clients, if it has changed
+/// since it was last published
+pub(crate) fn publish(control_state: &ControlState) {
+    let snapshot = Snapshot::from(control_state);
+    control_state.snapshots.send_if_modified(|published| {
+        let changed = *published != snapshot;
+        if changed {
+            *published = snapshot;
+        }
+        changed
+    });
+}
+
+async fn control_page() -> Html<String> {
+    Html(
+        CONTROL_PAGE
+            .replace("{MIN_FONT}", &MIN_FONT.to_string())
+            .replace("{MAX_FONT}", &MAX_FONT.to_string())
+            .replace("{MIN_SUBTITLE_HEIGHT}", &MIN_SUBTITLE_HEIGHT.to_string())
+            .replace("{MAX_SUBTITLE_HEIGHT}", &MAX_SUBTITLE_HEIGHT.to_string()),
+    )
+}
+
+async fn font() -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "font/ttf")], NOTO_SANS)
+}
+
+async fn state(State(control_state): State<Shared>) -> ApiResult {
+    update(&control_state, |_| Ok(()))
+}
+
+async fn run(State(control_state): State<Shared>) -> ApiResult {
+    update(&control_state, |control_state| {
+        control_state.set_run_state(RunState::Running);
+        Ok(())
+    })
+}
+
+async fn stop(State(control_state): State<Shared>) -> ApiResult {
+    update(&control_state, |control_state| {
+        control_state.stop();
+        Ok(())
+    })
+}
+
+async fn test(State(control_state): State<Shared>) -> ApiResult {
+    update(&control_state, |control_state| {
+        control_state.set_run_state(RunState::Test);
+        Ok(())
+    })
+}
+
+async fn holding_slide(State(control_state): State<Shared>) -> ApiResult {
+    update(&control_state, |control_state| {
+        control_state.set_run_state(RunState::HoldingSlide);
+        Ok(())
+    })
+}
+
+async fn clear(State(control_state): State<Shared>) -> ApiResult {
+    update(&control_state, |control_state| {
+        control_state.apply(Action::Clear);
+        Ok(())
+    })
+}
+
+async fn font_size(
+    State(control_state): State<Shared>,
+    Json(Value { value }): Json<Value<f32>>,
+) -> ApiResult {
+    update(&control_state, |control_state| {
+        if !(MIN_FONT..=MAX_FONT).contains(&value) {
+            return Err(format!(
+                "Font size must be between {MIN_FONT} and {MAX_FONT}"
+            ));
+        }
+        *control_state.font_size_mut() = value;
+        Ok(())
+    })
+}
+
+async fn subtitle_height(
+    State(control_state): State<Shared>,
+    Json(Value { value }): Json<Value<f32>>,
+) -> ApiResult {
+    update(&control_state, |control_state| {
+        if !(MIN_SUBTITLE_HEIGHT..=MAX_SUBTITLE_HEIGHT).contains(&value) {
+            return Err(format!(
+                "Subtitle height must be between {MIN_SUBTITLE_HEIGHT} and \
+                {MAX_SUBTITLE_HEIGHT}"
+            ));
+        }
+        control_state.subtitle_height_proportion = value;
+        Ok(())
+    })
+}
+
+async fn dark_mode(
+    State(control_state): State<Shared>,
+    Json(Value { value }): Json<Value<bool>>,
+) -> ApiResult {
+    update(&control_state, |control_state| {
+        control_state.dark_mode_requested = value;
+        Ok(())
+    })
+}
+
+async fn display_mode(
+    State(control_state): State<Shared>,
+    Json(Value { value }): Json<Value<DisplayMode>>,
+) -> ApiResult {
+    update(&control_state, |control_state| {
+        control_state.display_mode = value;
+        Ok(())
+    })
+}
+
+async fn wordlist(
+    State(control_state): State<Shared>,
+    Json(Value { value }): Json<Value<Option<Arc<str>>>>,
+) -> ApiResult {
+    update(&control_state, |control_state| {
+        check_option(&control_state.wordlist_options, value.as_ref())?;
+        control_state.wordlist = value;
+        control_state.update_wordlist();
+        Ok(())
+    })
+}
+
+async fn selected_image(
+    State(control_state): State<Shared>,
+    Json(Value { value }): Json<Value<Option<Arc<str>>>>,
+) -> ApiResult {
+    update(&control_state, |control_state| {
+        check_option(&control_state.image_options, value.as_ref())?;
+        control_state.selected_image = value;
+        Ok(())
+    })
+}
+
+fn check_option(
+    options: &[Arc<str>],
+    choice: Option<&Arc<str>>,
+) -> Result<(), String> {
+    match choice {
+        Some(choice) if !options.contains(choice) => {
+            Err(format!("Unknown option `{choice}`"))
+        }
+        _ => Ok(()),
+    }
+}
+
+async fn websocket(
+    ws: WebSocketUpgrade,
+    State(control_state): State<Shared>,
+) -> Response {
+    ws.on_upgrade(|socket| push_state(socket, control_state))
+}
+
+async fn push_state(mut socket: WebSocket, control_state: Shared) {
+    let mut snapshots = {
+        let control_state = control_state.lock().unwrap();
+        publish(&control_state);
+        control_state.snapshots.subscribe()
+    };
+    // Start with the current state
+    snapshots.mark_changed();
+
+    loop {
+        tokio::select! {
+            changed = snapshots.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+                let json =
+                    serde_json::to_string(&*snapshots.borrow_and_update())
+                        .unwrap();
+                if socket.send(Message::Text(json.into())).await.is_err() {
+                    break;
+                }
+            }
+            msg = socket.recv() => {
+                match msg {
+                    Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                    Some(Ok(_)) => {}
+                }
+            }
+        }
+    }
+}
+
+async fn captions(
+    ws: WebSocketUpgrade,
+    State(captions_tx): State<broadcast::Sender<Caption>>,
+) -> Response {
+    let captions_rx = captions_tx.subscribe();
+    ws.on_upgrade(|socket| push_captions(socket, captions_rx))
+}
+
+async fn push_captions(
+    mut socket: WebSocket,
+    mut captions_rx: broadcast::Receiver<Caption>,
+) {
+    loop {
+        tokio::select! {
+            caption = captions_rx.recv() => {
+                let caption = match caption {
+                    Ok(caption) => caption,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("Caption viewer lagged by {skipped} lines");
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                let json = serde_json::to_string(&caption).unwrap();
+                if socket.send(Message::Text(json.into())).await.is_err() {
+                    break;
+                }
+            }
+            msg = socket.recv() => {
+                match msg {
+                    Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                    Some(Ok(_)) => {}
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        ControlMessage, Wordlist,
+        config::{AudioBackend, AudioConfig, Config},
+        gui::MyApp,
+        xrandr::MonitorPositions,
+    };
+    use axum::{
+        body::{Body, to_bytes},
+        http::Request,
+    };
+    use pretty_assertions::assert_eq;
+    use tokio::sync::{mpsc, watch};
+    use tower::ServiceExt;
+
+    struct Harness {
+        control_state: Shared,
+        control_rx: mpsc::Receiver<ControlMessage>,
+        router: Router,
+    }
+
+    impl Harness {
+        async fn start() -> Self {
+            let (control_tx, mut control_rx) = mpsc::channel(10);
+            let (captions_tx, _) = broadcast::channel(10);
+            let config = Config {
+                languages: vec!["en-GB".into()],
+                // Has no devices to list, so nothing is run to find them
+                audio: AudioConfig {
+                    backend: AudioBackend::Jack,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let (app, ()) = tokio::join!(
+                MyApp::new(
+                    mpsc::channel(1).1,
+                    captions_tx.clone(),
+                    config,
+                    control_tx,
+                    watch::channel(None).1,
+                    watch::channel(Default::default()).1,
+                    MonitorPositions {
+                        internal: Default::default(),
+                        external: Default::default(),
+                    },
+                ),
+                async {
+                    let Some(ControlMessage::GetWordlist(tx)) =
+                        control_rx.recv().await
+                    else {
+                        panic!("Expected the wordlist to be requested");
+                    };
+                    tx.send(Wordlist {
+                        options: vec!["names".into()],
+                        current: None,
+                    })
+                    .unwrap();
+                },
+            );
+            let control_state = app.unwrap().control_state();
+            Self {
+                router: router(Arc::clone(&control_state), captions_tx),
+                control_state,
+                control_rx,
+            }
+        }
+
+        async fn request(
+            &self,
+            method: &str,
+            uri: &str,
+            body: Option<serde_json::Value>,
+        ) -> (StatusCode, Option<serde_json::Value>) {
+            let request = Request::builder().method(method).uri(uri);
+            let request = match body {
+                Some(body) => request
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string())),
+                None => request.body(Body::empty()),
+            };
+            let response =
+                self.router.clone().oneshot(request.unwrap()).await.unwrap();
+            let status = response.status();
+            let body =
+                to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            (status, serde_json::from_slice(&body).ok())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_state() {
+        let harness = Harness::start().await;
+
+        let (status, snapshot) =
+            harness.request("GET", "/api/state", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let snapshot = snapshot.unwrap();
+        assert_eq!(snapshot["run_state"], "Stopped");
+        assert_eq!(snapshot["wordlist_options"], serde_json::json!(["names"]));
+        assert_eq!(snapshot["wordlist"], serde_json::Value::Null);
+    }
+
+    #[tokio::test]
+    async fn test_run_state() {
+        let mut harness = Harness::start().await;
+
+        let (status, snapshot) =
+            harness.request("POST", "/api/run", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(snapshot.unwrap()["run_state"], "Running");
+        assert!(matches!(
+            harness.control_rx.try_recv(),
+            Ok(ControlMessage::SetState(RunState::Running))
+        ));
+
+        let (_, snapshot) = harness.request("POST", "/api/stop", None).await;
+        assert_eq!(snapshot.unwrap()["run_state"], "Stopped");
+        assert!(matches!(
+            harness.control_rx.try_recv(),
+            Ok(ControlMessage::SetState(RunState::Stopped))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_font_size() {
+        let harness = Harness::start().await;
+
+        let (status, snapshot) = harness
+            .request(
+                "PUT",
+                "/api/font_size",
+                Some(serde_json::json!({"value": 60.0})),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(snapshot.unwrap()["font_size"], 60.0);
+
+        let (status, _) = harness
+            .request(
+                "PUT",
+                "/api/font_size",
+                Some(serde_json::json!({"value": MAX_FONT + 1.0})),
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(harness.control_state.lock().unwrap().font_size(), 60.0);
+    }
+
+    #[tokio::test]
+    async fn test_publish() {
+        let harness = Harness::start().await;
+        let mut snapshots =
+            harness.control_state.lock().unwrap().snapshots.subscribe();
+
+        harness
+            .request(
+                "PUT",
+                "/api/font_size",
+                Some(serde_json::json!({"value": 60.0})),
+            )
+            .await;
+        assert!(snapshots.has_changed().unwrap());
+        assert_eq!(snapshots.borrow_and_update().font_size, 60.0);
+
+        // Nothing is pushed unless something changed
+        harness.request("GET", "/api/state", None).await;
+        harness
+            .request(
+                "PUT",
+                "/api/font_size",
+                Some(serde_json::json!({"value": 60.0})),
+            )
+            .await;
+        assert!(!snapshots.has_changed().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_wordlist() {
+        let mut harness = Harness::start().await;
+
+        let (status, _) = harness
+            .request(
+                "PUT",
+                "/api/wordlist",
+                Some(serde_json::json!({"value": "unknown"})),
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(harness.control_rx.try_recv().is_err());
+
+        let (status, snapshot) = harness
+            .request(
+                "PUT",
+                "/api/wordlist",
+                Some(serde_json::json!({"value": "names"})),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(snapshot.unwrap()["wordlist"], "names");
+        assert!(matches!(
+            harness.control_rx.try_recv(),
+            Ok(ControlMessage::SetWordlist(Some(wordlist))) if &*wordlist == "names"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_speaker_names() {
+        let harness = Harness::start().await;
+        {
+            let mut control_state = harness.control_state.lock().unwrap();
+            control_state
+                .speaker_names
+                .insert("Guest-1".into(), "Alice".into());
+            control_state
+                .speaker_names
+                .insert("Guest-2".into(), String::new());
+        }
+
+        let (_, snapshot) = harness.request("GET", "/api/state", None).await;
+        assert_eq!(
+            snapshot.unwrap()["speaker_names"],
+            serde_json::json!({"Guest-1": "Alice", "Guest-2": "Guest-2"})
+        );
+    }
+
+    #[test]
+    fn test_caption_json() {
+        assert_eq!(
+            serde_json::to_value(Caption::Clear).unwrap(),
+            serde_json::json!("Clear")
+        );
+        let caption = Caption::Line(Line::Recognised("hello".into()));
+        assert_eq!(
+            serde_json::to_value(caption).unwrap()["Line"]["Recognised"]["text"],
+            "hello"
+        );
+    }
+}
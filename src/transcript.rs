@@ -0,0 +1,319 @@
+use crate::{Line, Result};
+use std::{
+    fmt::Write,
+    path::Path,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Finalised captions from a single Run session, exported as subtitle files
+/// when the session ends
+pub struct Transcript {
+    started_at: SystemTime,
+    started: Instant,
+    /// Time since the start of the session at which the current connection
+    /// to the backend was made. Backend offsets are relative to this.
+    connection_start: Duration,
+    /// When the current utterance was first heard, for backends which do
+    /// not report offsets
+    heard_at: Option<Duration>,
+    entries: Vec<Entry>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Entry {
+    /// Id of the line, for corrections made while moderating
+    id: Option<u64>,
+    start: Duration,
+    end: Duration,
+    text: String,
+}
+
+impl Transcript {
+    pub fn start() -> Self {
+        Self {
+            started_at: SystemTime::now(),
+            started: Instant::now(),
+            connection_start: Duration::ZERO,
+            heard_at: None,
+            entries: Vec::new(),
+        }
+    }
+
+    /// Mark the start of a new connection to the backend
+    pub fn connected(&mut self) {
+        self.connection_start = self.started.elapsed();
+        self.heard_at = None;
+    }
+
+    pub fn record(&mut self, line: &Line) {
+        let now = self.started.elapsed();
+        match line {
+            Line::Recognising(_) => {
+                self.heard_at.get_or_insert(now);
+            }
+            Line::Translated { .. } => {}
+            Line::Recognised(utterance) => {
+                let heard_at = self.heard_at.take();
+                if utterance.text.is_empty() {
+                    return;
+                }
+                let (start, end) = match (utterance.offset, utterance.duration)
+                {
+                    (Some(offset), Some(duration)) => {
+                        let start = self.connection_start + offset;
+                        (start, start + duration)
+                    }
+                    _ => (heard_at.unwrap_or(now), now),
+                };
+                self.entries.push(Entry {
+                    id: utterance.id,
+                    start,
+                    end,
+                    text: utterance.text.clone(),
+                });
+            }
+        }
+    }
+
+    /// Replace the text of a line which the operator corrected before it
+    /// was shown, or remove it if they dropped it instead. Returns whether
+    /// the line was in this transcript.
+    pub fn correct(&mut self, id: u64, text: Option<String>) -> bool {
+        let Some(index) =
+            self.entries.iter().position(|entry| entry.id == Some(id))
+        else {
+            return false;
+        };
+        match text.filter(|text| !text.is_empty()) {
+            Some(text) => self.entries[index].text = text,
+            None => {
+                self.entries.remove(index);
+            }
+        }
+        true
+    }
+
+    pub fn to_srt(&self) -> String {
+        let mut out = String::new();
+        for (idx, entry) in self.entries.iter().enumerate() {
+            let _ = write!(
+                out,
+                "{}\n{} --> {}\n{}\n\n",
+                idx + 1,
+                cue_time(entry.start, ','),
+                cue_time(entry.end, ','),
+                entry.text,
+            );
+        }
+        out
+    }
+
+    pub fn to_vtt(&self) -> String {
+        let mut out = String::from("WEBVTT\n\n");
+        for entry in &self.entries {
+            let _ = write!(
+                out,
+                "{} --> {}\n{}\n\n",
+                cue_time(entry.start, '.'),
+                cue_time(entry.end, '.'),
+                entry.text,
+            );
+        }
+        out
+    }
+
+    /// Write the transcript to `dir` as both SRT and WebVTT, named after
+    /// the time the session started. Saving again overwrites the files, or
+    /// removes them if every line has since been dropped.
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        let name = format!("transcript-{}", file_timestamp(self.started_at));
+        let srt = dir.join(format!("{name}.srt"));
+        let vtt = dir.join(format!("{name}.vtt"));
+        if self.entries.is_empty() {
+            for path in [srt, vtt] {
+                match std::fs::remove_file(path) {
+                    Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                        return Err(err.into());
+                    }
+                    _ => {}
+                }
+            }
+            return Ok(());
+        }
+
+        std::fs::create_dir_all(dir)?;
+        std::fs::write(&srt, self.to_srt())?;
+        std::fs::write(&vtt, self.to_vtt())?;
+        info!(
+            "Saved transcript to {} and {}",
+            srt.display(),
+            vtt.display()
+        );
+
+        Ok(())
+    }
+}
+
+/// `HH:MM:SS,mmm` for SRT or `HH:MM:SS.mmm` for WebVTT
+fn cue_time(time: Duration, separator: char) -> String {
+    let secs = time.as_secs();
+    format!(
+        "{:02}:{:02}:{:02}{separator}{:03}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        time.subsec_millis(),
+    )
+}
+
+/// UTC `YYYY-MM-DDTHH-MM-SS`, which is safe to use in file names
+pub fn file_timestamp(time: SystemTime) -> String {
+    utc_time(time, '-')
+}
+
+/// UTC `YYYY-MM-DDTHH:MM:SS.mmmZ`
+pub fn iso_timestamp(time: SystemTime) -> String {
+    let millis = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_millis();
+    format!("{}.{millis:03}Z", utc_time(time, ':'))
+}
+
+fn utc_time(time: SystemTime, separator: char) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let secs_of_day = secs % 86400;
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}{separator}{:02}{separator}{:02}",
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+    )
+}
+
+// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Utterance;
+    use pretty_assertions::assert_eq;
+
+    fn transcript() -> Transcript {
+        let mut transcript = Transcript::start();
+        transcript.record(&Line::Recognising("hello".into()));
+        transcript.record(&Line::Recognised(Utterance {
+            text: "Hello there.".into(),
+            offset: Some(Duration::from_millis(1500)),
+            duration: Some(Duration::from_millis(2250)),
+            ..Default::default()
+        }));
+        transcript.connection_start = Duration::from_secs(3600);
+        transcript.record(&Line::Recognised(Utterance {
+            text: "General Kenobi.".into(),
+            offset: Some(Duration::from_millis(61_001)),
+            duration: Some(Duration::from_millis(999)),
+            ..Default::default()
+        }));
+        transcript
+    }
+
+    #[test]
+    fn test_srt() {
+        assert_eq!(
+            transcript().to_srt(),
+            "\
+1
+00:00:01,500 --> 00:00:03,750
+Hello there.
+
+2
+01:01:01,001 --> 01:01:02,000
+General Kenobi.
+
+"
+        );
+    }
+
+    #[test]
+    fn test_vtt() {
+        assert_eq!(
+            transcript().to_vtt(),
+            "\
+WEBVTT
+
+00:00:01.500 --> 00:00:03.750
+Hello there.
+
+01:01:01.001 --> 01:01:02.000
+General Kenobi.
+
+"
+        );
+    }
+
+    #[test]
+    fn test_correct() {
+        let mut transcript = Transcript::start();
+        for (id, text) in ["one", "two", "three"].into_iter().enumerate() {
+            transcript.record(&Line::Recognised(Utterance {
+                id: Some(id as u64),
+                offset: Some(Duration::from_secs(id as u64)),
+                duration: Some(Duration::from_millis(500)),
+                ..text.into()
+            }));
+        }
+
+        assert!(transcript.correct(0, Some("won".into())));
+        assert!(transcript.correct(1, None));
+        // Lines from before the transcript started are left alone
+        assert!(!transcript.correct(7, None));
+        assert_eq!(
+            transcript.to_vtt(),
+            "\
+WEBVTT
+
+00:00:00.000 --> 00:00:00.500
+won
+
+00:00:02.000 --> 00:00:02.500
+three
+
+"
+        );
+    }
+
+    #[test]
+    fn test_file_timestamp() {
+        assert_eq!(
+            file_timestamp(UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+            "2023-11-14T22-13-20"
+        );
+        assert_eq!(
+            file_timestamp(UNIX_EPOCH + Duration::from_secs(951_782_400)),
+            "2000-02-29T00-00-00"
+        );
+        assert_eq!(
+            iso_timestamp(
+                UNIX_EPOCH + Duration::from_millis(1_700_000_000_042)
+            ),
+            "2023-11-14T22:13:20.042Z"
+        );
+    }
+}